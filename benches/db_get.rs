@@ -0,0 +1,38 @@
+use ckv::db::db::DB;
+use ckv::utils::test_helper;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::sync::Arc;
+
+// db_get measures the cost of a lookup as a level grows, the keys are
+// compacted to level 1, a table of sstable_maxsz holds about 40 of them, so
+// the level holds about 25, 400 and 6400 tables
+fn db_get(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("db_get");
+    for num_keys in [1u64 << 10, 1 << 14, 1 << 18] {
+        let mut opt = test_helper::test_options("bench_db_get");
+        opt.memtable_size = 64 << 20;
+        opt.max_level_num = 2;
+        // every table stays open, so the table cache does not take part
+        opt.max_open_files = 0;
+        let db = DB::open(Arc::new(opt)).unwrap();
+        for i in 0..num_keys {
+            let key = format!("key{:08}", i * 2);
+            db.set(&key, &key).unwrap();
+        }
+        rt.block_on(db.compact_range::<&[u8]>(None, None)).unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(num_keys), &num_keys, |b, &n| {
+            let mut i = 0u64;
+            b.iter(|| {
+                i = (i + 7919) % n;
+                let key = format!("key{:08}", i * 2);
+                assert!(db.get(&key).unwrap().is_some());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, db_get);
+criterion_main!(benches);
@@ -1,6 +1,4 @@
-use rand::{seq::IteratorRandom, Rng};
-use rand::seq::SliceRandom;
-use std::time::{SystemTime, UNIX_EPOCH};
+use rand::seq::IteratorRandom;
 
 fn rand_str(length: usize) -> String {
     // Define the characters to choose from (including special characters and emojis)
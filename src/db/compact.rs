@@ -1,20 +1,20 @@
+use super::format::{self, ValueType};
 use super::iterator::*;
 use super::level::*;
 use super::options::Options;
-use crate::file::manifest::*;
+use crate::error::Result;
 use crate::pb::pb::{ManifestChange, ManifestChangeSet};
 use crate::table::table::Table;
 use crate::table::table_builder::TableBuilder;
 use crate::utils::file::file_helper;
 use crate::utils::slice::Slice;
-use prost::Message;
 use rand::Rng;
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use tokio::sync::mpsc;
-use tokio::time::{interval, sleep};
+use tokio::sync::{mpsc, watch};
+use tokio::time::sleep;
 
 #[derive(Clone, Debug)]
 struct CompactionPriority {
@@ -68,7 +68,7 @@ struct KeyRange {
 }
 
 impl LevelManager {
-    fn level_targets(&self) -> Result<Targets, String> {
+    fn level_targets(&self) -> Targets {
         let adjust = |sz: u64| std::cmp::max(sz, self.opt.base_level_size);
 
         let mut t = Targets {
@@ -111,13 +111,13 @@ impl LevelManager {
             t.base_level = i as u32;
         }
 
-        Ok(t)
+        t
     }
 
     // selects an appropriate level to perform a compaction
     // and returns the priority of the decision
-    fn pick_compact_levels(&self) -> Result<Vec<CompactionPriority>, String> {
-        let t = self.level_targets()?;
+    fn pick_compact_levels(&self) -> Vec<CompactionPriority> {
+        let t = self.level_targets();
 
         let mut prios = Vec::new();
         let mut add_priority = |level: u32, score: f64| {
@@ -137,8 +137,6 @@ impl LevelManager {
             0,
             self.get_level_num_tables(0) as f64 / self.opt.num_level_zero_tables as f64,
         );
-        let level_0 = self.levels[0].read().map_err(|e| e.to_string())?;
-
         // Non-l0 levels calculate priority based on size
         let len = self.levels.len();
         for i in 1..len {
@@ -161,7 +159,7 @@ impl LevelManager {
 
         out.sort_by(|i, j| i.adjusted.partial_cmp(&j.adjusted).unwrap());
 
-        Ok(out)
+        out
     }
 
     fn move_l0_to_front(prios: Vec<CompactionPriority>) -> Vec<CompactionPriority> {
@@ -185,10 +183,13 @@ impl LevelManager {
         prios
     }
 
-    // L0 to L0 table compression
-    fn fill_tables_l0_to_l0(&self, cd: &mut CompactDef) -> Result<(), String> {
+    // L0 to L0 table compression, fill_tables_* return whether tables to
+    // compact are picked
+    fn fill_tables_l0_to_l0(&self, cd: &mut CompactDef) -> bool {
+        // Only the 0th compression processor can excute, to avoid resource
+        // contention in L0 to L0 compression
         if cd.compact_id != 0 {
-            return Err("Only the 0th compression processor can excute, to avoid resource contention in L0 to L0 compression ".to_string());
+            return false;
         }
 
         cd.next_level = 0;
@@ -219,58 +220,60 @@ impl LevelManager {
 
         if out.len() < 4 {
             // not compressing if the number of ssts that meet the condition is less than 4
-            return Err("too few files".to_string());
+            return false;
         }
 
         let mut cs = self.compact_state.write().unwrap();
-        for idx in out {
+        for &idx in &out {
             cs.tables.insert(tables[idx].id().unwrap());
+            cd.tables.push(tables[idx].id().unwrap());
+            cd.this_range.extend(KeyRange::with_table(&tables[idx]));
         }
+        cd.top = out.into_iter().map(|i| i as u32).collect();
+        cd.next_range = cd.this_range.clone();
 
         cd.t.file_sz[0] = u64::MAX;
-        Ok(())
+        true
     }
 
-    fn fill_tables_l0_to_base(&self, cd: &mut CompactDef) -> Result<(), String> {
+    fn fill_tables_l0_to_base(&self, cd: &mut CompactDef) -> bool {
+        // base level cannot be zero
         if cd.next_level == 0 {
-            return Err("base level cannot be zero".to_string());
+            return false;
         }
 
         // if priority is less than 1, not executed{
         if cd.p.adjusted > 0.0 && cd.p.adjusted < 1.0 {
-            return Err("adjusted score is less than 1.0".to_string());
+            return false;
         }
 
         let top = &self.levels[cd.this_level as usize].read().unwrap().tables;
 
-        if top.len() == 0 {
-            return Err("top level empty".to_string());
+        if top.is_empty() {
+            return false;
         }
 
-        let mut out = Vec::new();
-        let mut kr = KeyRange::new();
-        // cd.top[0] is the oldest file, start from the oldest file
-        for (i, table) in top.iter().enumerate() {
-            let dkr = KeyRange::with_table(table);
-            if kr.overlap_with(&dkr) {
-                out.push(i as u32);
-                kr.extend(dkr);
-                cd.tables.push(table.id().unwrap());
-            } else {
-                // terminal if any range not overlap
-                break;
-            }
+        // start from the oldest file, top[0], a table compacted inside
+        // level 0 has the newest id but may hold older entries, so every
+        // table that overlaps is picked, not only the ones next to it
+        let mut out = vec![0];
+        let mut kr = KeyRange::with_table(&top[0]);
+        Self::add_l0_overlapping(top, &mut out, &mut kr);
+        out.sort();
+        for &i in &out {
+            cd.tables.push(top[i as usize].id().unwrap());
         }
 
         cd.top = out;
         cd.this_range = kr;
-        if let Ok((left, right)) =
+        cd.next_range = cd.this_range.clone();
+        if let Some((left, right)) =
             self.get_level_overlapping_tables(cd.next_level as usize, &cd.this_range)
         {
             let v: Vec<u32> = (left as u32..=right as u32).collect();
 
             let bot = &self.levels[cd.next_level as usize].read().unwrap().tables;
-            let bot: Vec<&Table> = v.iter().map(|&i| &bot[i as usize]).collect();
+            let bot: Vec<&Table> = v.iter().map(|&i| bot[i as usize].as_ref()).collect();
             cd.bot = v;
             cd.next_range = KeyRange::with_tables(&bot);
             for table in bot {
@@ -279,30 +282,54 @@ impl LevelManager {
             }
         }
 
-        self.compact_state.write().unwrap().compare_and_add(&cd)
+        self.compact_state.write().unwrap().compare_and_add(cd)
     }
 
-    fn fill_tables(&self, cd: &mut CompactDef) -> Result<(), String> {
+    // tables in level 0 overlap, the tables that overlap the picked ones
+    // are picked too, or an older version left in level 0 would shadow
+    // the newer one moved down
+    fn add_l0_overlapping(tables: &[Arc<Table>], top: &mut Vec<u32>, kr: &mut KeyRange) {
+        loop {
+            let more: Vec<u32> = (0..tables.len() as u32)
+                .filter(|i| !top.contains(i))
+                .filter(|&i| kr.overlap_with(&KeyRange::with_table(&tables[i as usize])))
+                .collect();
+            if more.is_empty() {
+                break;
+            }
+            for i in more {
+                kr.extend(KeyRange::with_table(&tables[i as usize]));
+                top.push(i);
+            }
+        }
+    }
+
+    fn fill_tables(&self, cd: &mut CompactDef) -> bool {
         let tables = &self.levels[cd.this_level as usize].read().unwrap().tables;
-        if tables.len() == 0 {
-            return Err("this level is empty".to_string());
+        if tables.is_empty() {
+            return false;
         }
 
         for (i, table) in tables.iter().enumerate() {
             cd.this_sz = table.size();
             cd.this_range = KeyRange::with_table(table);
+            cd.top = vec![i as u32];
+            cd.tables = vec![table.id().unwrap()];
             // do nothing if has been compressing
             {
-                if self.compact_state_overlap_with(cd.this_level as usize, &cd.this_range) == true {
+                if self.compact_state_overlap_with(cd.this_level as usize, &cd.this_range) {
                     continue;
                 }
             }
-            if let Ok((left, right)) =
+            cd.bot = Vec::new();
+            // if no table overlaps in next level, the table is just moved down
+            cd.next_range = cd.this_range.clone();
+            if let Some((left, right)) =
                 self.get_level_overlapping_tables(cd.next_level as usize, &cd.this_range)
             {
                 let v: Vec<u32> = (left..=right).map(|i| i as u32).collect();
                 let bot = &self.levels[cd.next_level as usize].read().unwrap().tables;
-                let bot: Vec<&Table> = v.iter().map(|&i| &bot[i as usize]).collect();
+                let bot: Vec<&Table> = v.iter().map(|&i| bot[i as usize].as_ref()).collect();
 
                 cd.bot = v;
                 cd.next_range = KeyRange::with_tables(&bot);
@@ -310,28 +337,24 @@ impl LevelManager {
                     cd.this_sz += table.size();
                     cd.tables.push(table.id().unwrap());
                 }
-                if let Ok(()) = self.compact_state.write().unwrap().compare_and_add(&cd) {
-                    return Ok(());
-                } else {
-                    continue;
-                }
+            }
+            if self.compact_state.write().unwrap().compare_and_add(cd) {
+                return true;
             }
         }
 
-        Err("no overlap".to_string())
+        // no table to compact
+        false
     }
 
     // fill_tables_l0 first try L0 to L_base compressing, if failed
     // compressing L0 to L0.
-    fn fill_tables_l0(&self, cd: &mut CompactDef) -> Result<(), String> {
-        if let Ok(()) = self.fill_tables_l0_to_base(cd) {
-            Ok(())
-        } else {
-            self.fill_tables_l0_to_l0(cd)
-        }
+    fn fill_tables_l0(&self, cd: &mut CompactDef) -> bool {
+        self.fill_tables_l0_to_base(cd) || self.fill_tables_l0_to_l0(cd)
     }
 
-    // parallel execution of sub-compression scenarios
+    // parallel execution of sub-compression scenarios, a split holds the user keys
+    // in (left, right], an empty left or right means the range is unbounded on it
     fn add_splits(&self, cd: &mut CompactDef) {
         // Let's say we have 10 tables in cd.bot and min width = 3. Then, we'll pick
         // 0, 1, 2 (pick), 3, 4, 5 (pick), 6, 7, 8 (pick), 9 (pick, because last table).
@@ -343,47 +366,43 @@ impl LevelManager {
         if width < 3 {
             width = 3;
         }
-        let mut skr = cd.this_range.clone();
-        skr.extend(cd.next_range.clone());
+        let mut skr = KeyRange::new();
 
         if cd.this_level == cd.next_level {
             cd.splits.push(skr);
             return;
         }
 
-        let right_bound = skr.right.clone();
-
-        let mut add_range = |right: &Slice| {
-            skr.right = right.clone();
+        let mut add_range = |right: &[u8]| {
+            skr.right = right.to_vec();
             cd.splits.push(skr.clone());
             skr.left = skr.right.clone();
         };
 
         let tables = &self.levels[cd.next_level as usize].read().unwrap().tables;
-        for (idx, table) in tables.iter().enumerate() {
-            // last entry in bottom table
-            if idx == tables.len() - 1 {
-                add_range(table.max_key());
-                if table.max_key().cmp(&right_bound).is_eq() {
-                    return;
-                }
+        for (idx, &i) in cd.bot.iter().enumerate() {
+            // the last split takes the rest keys
+            if idx == cd.bot.len() - 1 {
+                break;
             }
             if idx as u32 % width == width - 1 {
                 // set max key is right interval
-                add_range(table.max_key())
+                add_range(format::user_key(tables[i as usize].max_key()))
             }
         }
 
-        add_range(&right_bound);
+        add_range(&[]);
     }
 
-    pub async fn run_compacter(&self, id: u32) {
-        // debug!
-        println!("run compacter id : {}", id);
-
+    // run_compacter runs compactions until closer turns true, a compaction in
+    // progress always finishes before it returns
+    pub async fn run_compacter(&self, id: u32, mut closer: watch::Receiver<bool>) {
         // simulate random delay before starting the compaction process
         let random_delay = rand::thread_rng().gen_range(0..1000);
-        sleep(Duration::from_millis(random_delay as u64)).await;
+        tokio::select! {
+            _ = sleep(Duration::from_millis(random_delay as u64)) => {}
+            _ = closer.changed() => return,
+        }
 
         // set up the periodic compaction ticker(every 5 seconds)
         let mut ticker = tokio::time::interval(Duration::from_millis(5000));
@@ -391,19 +410,19 @@ impl LevelManager {
             tokio::select! {
                 // perform compaction once when the ticker triggers
 
+                // a failed compaction keeps its input tables, it is picked
+                // again on a later tick
                 _= ticker.tick()=>{
-                    if let Err(e) = self.run_once(id).await{
-                        println!("{e}");
-                    }
+                    let _ = self.run_once(id).await;
                 }
+                _ = closer.changed() => return,
             }
         }
     }
 
-    async fn run_once(&self, id: u32) -> Result<(), String> {
-        // debug
-        println!("compact run once id : {}", id);
-        let mut prios = self.pick_compact_levels()?;
+    // run_once runs a compaction if any level needs one
+    pub(crate) async fn run_once(&self, id: u32) -> Result<()> {
+        let mut prios = self.pick_compact_levels();
 
         if id == 0 {
             // No.0 corountine, always tends to compress L0
@@ -414,10 +433,106 @@ impl LevelManager {
                 return self.do_compact(id, p).await;
             }
         }
-        Err("no compact".to_string())
+        Ok(())
+    }
+
+    // compact_range compacts the tables that hold user keys in [start, end]
+    // level by level down to the bottommost level, a missing bound is unbounded.
+    // a level waits while its tables are held by a background compaction
+    pub(crate) async fn compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<()> {
+        let id = self.opt.num_compactors;
+        for level in 0..self.levels.len() as u32 - 1 {
+            loop {
+                let t = self.level_targets();
+                let mut cd = CompactDef {
+                    compact_id: id,
+                    t: t.clone(),
+                    this_level: level,
+                    p: CompactionPriority {
+                        level,
+                        score: 0.0,
+                        adjusted: 0.0,
+                        t,
+                    },
+                    next_level: level + 1,
+                    this_sz: 0,
+                    tables: Vec::new(),
+                    splits: Vec::new(),
+                    top: Vec::new(),
+                    bot: Vec::new(),
+                    this_range: KeyRange::new(),
+                    next_range: KeyRange::new(),
+                };
+                if !self.fill_tables_range(&mut cd, start, end) {
+                    break;
+                }
+                if self.compact_state.write().unwrap().compare_and_add(&cd) {
+                    self.run_compact_def(&mut cd).await?;
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+        Ok(())
     }
 
-    async fn do_compact(&self, id: u32, p: CompactionPriority) -> Result<(), String> {
+    // fill_tables_range picks the tables of this level that hold user keys in
+    // [start, end] and the tables of next level they overlap, return false if
+    // no table of this level is in the range
+    fn fill_tables_range(
+        &self,
+        cd: &mut CompactDef,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> bool {
+        let tables = &self.levels[cd.this_level as usize].read().unwrap().tables;
+        let in_range = |table: &Table| {
+            start.is_none_or(|start| format::user_key(table.max_key()) >= start)
+                && end.is_none_or(|end| format::user_key(table.min_key()) <= end)
+        };
+        let mut top: Vec<u32> = (0..tables.len() as u32)
+            .filter(|&i| in_range(&tables[i as usize]))
+            .collect();
+        if top.is_empty() {
+            return false;
+        }
+        let mut kr = KeyRange::new();
+        for &i in &top {
+            kr.extend(KeyRange::with_table(&tables[i as usize]));
+        }
+
+        if cd.this_level == 0 {
+            Self::add_l0_overlapping(tables, &mut top, &mut kr);
+        }
+        top.sort();
+
+        for &i in &top {
+            cd.this_sz += tables[i as usize].size();
+            cd.tables.push(tables[i as usize].id().unwrap());
+        }
+        cd.top = top;
+        cd.this_range = kr;
+        cd.next_range = cd.this_range.clone();
+        if let Some((left, right)) =
+            self.get_level_overlapping_tables(cd.next_level as usize, &cd.this_range)
+        {
+            let bot = &self.levels[cd.next_level as usize].read().unwrap().tables;
+            cd.bot = (left as u32..=right as u32).collect();
+            let bot: Vec<&Table> = cd.bot.iter().map(|&i| bot[i as usize].as_ref()).collect();
+            cd.next_range = KeyRange::with_tables(&bot);
+            for table in bot {
+                cd.this_sz += table.size();
+                cd.tables.push(table.id().unwrap());
+            }
+        }
+        true
+    }
+
+    async fn do_compact(&self, id: u32, p: CompactionPriority) -> Result<()> {
         let l = p.level;
         let base_level = p.t.base_level;
         // crate real compressing plan
@@ -437,32 +552,41 @@ impl LevelManager {
         };
 
         if l == 0 {
-            cd.next_level = base_level;
-            self.fill_tables_l0(&mut cd)?;
+            // a level above the base level may hold tables, e.g. left by
+            // compact_range, level 0 goes to the first of them, or the newer
+            // entries would be below the older ones
+            cd.next_level = (1..base_level)
+                .find(|&i| self.get_level_num_tables(i as usize) > 0)
+                .unwrap_or(base_level);
+            if !self.fill_tables_l0(&mut cd) {
+                return Ok(());
+            }
         } else {
             cd.next_level = cd.this_level;
 
-            if cd.this_level != self.levels.len() as u32 {
+            if cd.this_level != self.levels.len() as u32 - 1 {
                 cd.next_level = cd.this_level + 1;
-                self.fill_tables(&mut cd)?;
+                if !self.fill_tables(&mut cd) {
+                    return Ok(());
+                }
             }
         }
 
-        self.run_compact_def(id, &mut cd).await?;
+        self.run_compact_def(&mut cd).await?;
 
         Ok(())
     }
 
-    async fn run_compact_def(&self, id: u32, cd: &mut CompactDef) -> Result<(), String> {
-        //debug !
-        println!("run compact def start {:?}\n\n\n", cd);
-
-        let this_level = cd.this_level;
-        let next_level = cd.next_level;
-
+    async fn run_compact_def(&self, cd: &mut CompactDef) -> Result<()> {
         self.add_splits(cd);
 
-        let new_tables = self.compact_build_tables(cd).await;
+        let res = self.install_compact_def(cd).await;
+        self.compact_state.write().unwrap().delete(cd);
+        res
+    }
+
+    async fn install_compact_def(&self, cd: &CompactDef) -> Result<()> {
+        let new_tables = self.compact_build_tables(cd).await?;
 
         let change_set = Self::build_change_set(cd, &new_tables);
         self.manifest_file
@@ -470,135 +594,165 @@ impl LevelManager {
             .unwrap()
             .add_changes(change_set.changes)?;
 
-        let new_tables_id: Vec<u64> = new_tables.iter().map(|table| table.id().unwrap()).collect();
-
-        //debug !
-        println!("run compact def before delete {:?}", cd);
-
         self.replace_level_tables(cd.next_level, cd.bot.clone(), new_tables);
         self.delete_level_tables(cd.this_level, cd.top.clone());
-
-        println!(
-            "create new tables \n : {:?}\n delete tables :{:?}",
-            new_tables_id, cd.tables
-        );
-
         Ok(())
     }
 
-    // compact_build_tables merge two level ssts
-    async fn compact_build_tables(&self, cd: &CompactDef) -> Vec<Table> {
+    // compact_build_tables merge two level ssts, the tables built before a
+    // failure are left to be removed when the db opens
+    async fn compact_build_tables(&self, cd: &CompactDef) -> Result<Vec<Table>> {
         // start parallel compression
-        let (tx, mut rx) = mpsc::channel::<Table>(3);
+        let (tx, mut rx) = mpsc::channel::<Result<Table>>(3);
+        let mut skr = cd.this_range.clone();
+        skr.extend(cd.next_range.clone());
+        let drop_tombstones = self.is_bottommost(cd, &skr);
+        let snapshots = self.snapshots.seqs();
+        let level = cd.next_level;
         for kr in &cd.splits {
             let tx = tx.clone();
-            let top = self.levels[cd.this_level as usize].clone();
-            let bot = self.levels[cd.next_level as usize].clone();
-            let cd = cd.clone();
+            let iters = self.compact_iterators(cd);
             let kr = kr.clone();
             let opt = self.opt.clone();
+            let snapshots = snapshots.clone();
             tokio::spawn(async move {
-                Self::sub_compact(top, bot, cd, kr, tx, opt).await;
+                Self::sub_compact(iters, kr, level, drop_tombstones, snapshots, tx, opt).await;
             });
         }
         drop(tx);
 
         let mut tables = Vec::new();
         while let Some(table) = rx.recv().await {
-            tables.push(table);
+            tables.push(table?);
         }
-        tables.sort_by(|i, j| i.max_key().cmp(j.max_key()));
-        tables
+        tables.sort_by(|i, j| format::compare_internal_key(i.min_key(), j.min_key()));
+        Ok(tables)
+    }
+
+    // iterators of the top and bot tables of a compaction
+    fn compact_iterators(&self, cd: &CompactDef) -> Vec<BoxedIterator> {
+        let top_tables = &self.levels[cd.this_level as usize].read().unwrap().tables;
+        let bot_tables = &self.levels[cd.next_level as usize].read().unwrap().tables;
+
+        let mut v: Vec<BoxedIterator> = Vec::new();
+        for &i in &cd.top {
+            v.push(Box::new(top_tables[i as usize].new_iterator()));
+        }
+        for &i in &cd.bot {
+            v.push(Box::new(bot_tables[i as usize].new_iterator()));
+        }
+        v
     }
 
     async fn sub_compact(
-        top: Level,
-        bot: Level,
-        cd: CompactDef,
+        iters: Vec<BoxedIterator>,
         kr: KeyRange,
-        tx: mpsc::Sender<Table>,
+        level: u32,
+        drop_tombstones: bool,
+        snapshots: Vec<u64>,
+        tx: mpsc::Sender<Result<Table>>,
         opt: Arc<Options>,
     ) {
-        let top_tables = &top.read().unwrap().tables;
-        let bot_tables = &bot.read().unwrap().tables;
+        let mut merge_iter = MergeIterator::new(iters);
 
-        let mut v = Vec::new();
-        for &i in &cd.top {
-            v.push(top_tables[i as usize].new_iterator());
+        // if key range left live, seek to it, the left is exclusive
+        if !kr.left.is_empty() {
+            merge_iter.seek(&format::make_internal_key(&kr.left, 0, ValueType::Deletion));
+        } else {
+            merge_iter.seek_to_first();
         }
-        for &i in &cd.bot {
-            v.push(bot_tables[i as usize].new_iterator());
-        }
-
-        let mut merge_iter = MergeIterator::new(v);
-        let mut last_key = Slice::new();
-        let mut add_keys = |iter: &mut MergeIterator, builder: &mut TableBuilder| -> bool {
-            //let mut table_kr = KeyRange::new();
-            while let Some((key, val)) = iter.next() {
-                if key.cmp(&last_key) != Ordering::Equal {
-                    if val.is_empty() {
-                        last_key = key.clone();
-                        continue;
-                    }
-
-                    // set tmp key to last_key
-                    last_key = key.clone();
-
-                    // // if left boundary is left, give tmp key to left boundary
-                    // if table_kr.left.is_empty() {
-                    //     table_kr.left = key.clone();
-                    // }
-
-                    // // update right boundary
-                    // table_kr.right = last_key.clone();
-
-                    builder.add(&key, &val);
-
-                    // key range in iter greater or equal than tmp kr, break
-                    if !kr.right.is_empty() && key.cmp(&kr.right).is_ge() {
-                        return true;
-                    }
-                    if builder.reach_capacity() {
-                        return false;
-                    }
+
+        // tables are built for the level compacted to
+        let mut table_builder = TableBuilder::with_level(opt.clone(), level);
+        let mut last_key: Option<Slice> = None;
+        // the seq of the newer version of the same user key
+        let mut last_seq = format::MAX_SEQUENCE;
+        for (key, val) in merge_iter.by_ref() {
+            let (user_key, seq, kind) = match format::parse_internal_key(&key) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            if !kr.left.is_empty() && user_key.cmp(&kr.left).is_le() {
+                continue;
+            }
+            // key range in iter greater than tmp kr, break
+            if !kr.right.is_empty() && user_key.cmp(&kr.right).is_gt() {
+                break;
+            }
+
+            if last_key.as_deref() != Some(user_key) {
+                last_key = Some(user_key.to_vec());
+                last_seq = format::MAX_SEQUENCE;
+
+                // only cut tables between user keys, so versions of a user key
+                // never span tables in a level
+                if table_builder.reach_capacity() {
+                    let builder = std::mem::replace(
+                        &mut table_builder,
+                        TableBuilder::with_level(opt.clone(), level),
+                    );
+                    let opt = opt.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        Self::build_table(opt, builder, level, tx).await;
+                    });
                 }
             }
-            true
-        };
+            let newer_seq = std::mem::replace(&mut last_seq, seq);
+
+            // the newest version is always kept, an older one is kept only
+            // while a live snapshot between it and the newer version sees it
+            if newer_seq != format::MAX_SEQUENCE
+                && !snapshots.iter().any(|&s| seq <= s && s < newer_seq)
+            {
+                continue;
+            }
 
-        // if key range left live, seek to it
-        if kr.left.is_empty() == false {
-            merge_iter.seek(&kr.left);
+            // nothing older is left to shadow in the bottommost level, unless
+            // a snapshot still sees an older version below the tombstone
+            if drop_tombstones
+                && kind == ValueType::Deletion
+                && snapshots.first().is_none_or(|&s| seq <= s)
+            {
+                continue;
+            }
+
+            table_builder.add(&key, &val);
         }
 
-        loop {
-            let mut table_builder = TableBuilder::new(opt.clone());
-            let res = add_keys(&mut merge_iter, &mut table_builder);
-            let opt = opt.clone();
-            let tx = tx.clone();
+        // an input that failed to read ends the merge early, the tables built
+        // from it would lose the rest of the inputs, so the compaction fails
+        // and the inputs are kept
+        if let Err(e) = merge_iter.status() {
+            let _ = tx.send(Err(e)).await;
+            return;
+        }
+        if !table_builder.is_empty() {
             tokio::spawn(async move {
-                Self::build_table(opt, table_builder, tx).await;
+                Self::build_table(opt, table_builder, level, tx).await;
             });
-            if res {
-                return;
-            }
         }
     }
 
     // a corountine to help build table
-    async fn build_table(opt: Arc<Options>, table_builder: TableBuilder, tx: mpsc::Sender<Table>) {
-        opt.max_fid
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-        let new_id = opt.max_fid.load(std::sync::atomic::Ordering::Relaxed);
+    async fn build_table(
+        opt: Arc<Options>,
+        table_builder: TableBuilder,
+        level: u32,
+        tx: mpsc::Sender<Result<Table>>,
+    ) {
+        // builders run in parallel, each takes the id it added
+        let new_id = opt
+            .max_fid
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
 
         let sst_name = file_helper::file_sstable_name(new_id);
 
-        let table = Table::open(opt.clone(), sst_name, Some(table_builder)).unwrap();
-
-        println!("table id is {}", table.id().unwrap());
+        let table = Table::open(opt.clone(), sst_name, Some(table_builder), level);
 
-        tx.send(table).await.unwrap();
+        // the receiver is gone if the compaction has failed
+        let _ = tx.send(table).await;
     }
 
     // build changeset
@@ -632,28 +786,50 @@ impl LevelManager {
     }
 
     // returns the tables that intersect with key range
-    fn get_level_overlapping_tables(
-        &self,
-        idx: usize,
-        kr: &KeyRange,
-    ) -> Result<(usize, usize), String> {
+    fn get_level_overlapping_tables(&self, idx: usize, kr: &KeyRange) -> Option<(usize, usize)> {
         if kr.left.is_empty() || kr.right.is_empty() {
-            return Err("kr is empty".to_string());
+            return None;
         }
         let level = self.levels[idx].read().unwrap();
-        let len = level.tables.len();
-        let v: Vec<usize> = (0..len).collect();
 
-        let left = v
-            .binary_search_by(|&i| kr.left.cmp(level.tables[i].max_key()))
-            .map_err(|e| format!("kr.left > max key of tables, {}", e))?;
-        let right = v.binary_search_by(|&i| kr.right.cmp(level.tables[i].max_key()));
+        // tables in level are sorted and not overlapping
+        let left = level
+            .tables
+            .partition_point(|t| format::user_key(t.max_key()).cmp(&kr.left).is_lt());
+        let right = level
+            .tables
+            .partition_point(|t| format::user_key(t.min_key()).cmp(&kr.right).is_le());
+
+        // no table overlaps with kr
+        if left >= right {
+            return None;
+        }
+        Some((left, right - 1))
+    }
 
-        if let Ok(r) = right {
-            Ok((left, r))
-        } else {
-            Ok((left, len - 1))
+    // a key range is in the bottommost level if no deeper level holds keys of
+    // it, and, for a compaction of level 0, no table of level 0 left out of it
+    // does, as a table too large, too young or held by another compaction may
+    // hold older versions under a tombstone
+    fn is_bottommost(&self, cd: &CompactDef, kr: &KeyRange) -> bool {
+        if cd.this_level == 0 {
+            let tables = &self.levels[0].read().unwrap().tables;
+            for table in tables {
+                let input = table.id().is_ok_and(|id| cd.tables.contains(&id));
+                if !input && kr.overlap_with(&KeyRange::with_table(table)) {
+                    return false;
+                }
+            }
+        }
+        for i in (cd.next_level as usize + 1)..self.levels.len() {
+            let tables = &self.levels[i].read().unwrap().tables;
+            for table in tables {
+                if kr.overlap_with(&KeyRange::with_table(table)) {
+                    return false;
+                }
+            }
         }
+        true
     }
 
     fn get_compact_delsize(&self, idx: usize) -> u64 {
@@ -661,7 +837,7 @@ impl LevelManager {
     }
 
     fn compact_state_overlap_with(&self, idx: usize, kr: &KeyRange) -> bool {
-        let cs = self.compact_state.write().unwrap();
+        let cs = self.compact_state.read().unwrap();
         cs.levels[idx].overlap_with(kr)
     }
 }
@@ -673,10 +849,11 @@ impl KeyRange {
             right: Vec::new(),
         }
     }
+    // key range holds user keys of the table
     pub fn with_table(table: &Table) -> Self {
         KeyRange {
-            left: (*table.min_key()).clone(),
-            right: (*table.max_key()).clone(),
+            left: format::user_key(table.min_key()).to_vec(),
+            right: format::user_key(table.max_key()).to_vec(),
         }
     }
 
@@ -684,20 +861,11 @@ impl KeyRange {
         if tables.is_empty() {
             return KeyRange::new();
         }
-        let mut min_key = tables[0].min_key();
-        let mut max_key = tables[1].max_key();
+        let mut kr = KeyRange::with_table(tables[0]);
         for table in tables {
-            if table.min_key().cmp(min_key) == Ordering::Less {
-                min_key = table.min_key();
-            }
-            if table.max_key().cmp(max_key) == Ordering::Greater {
-                max_key = table.max_key();
-            }
-        }
-        KeyRange {
-            left: (*min_key).clone(),
-            right: (*max_key).clone(),
+            kr.extend(KeyRange::with_table(table));
         }
+        kr
     }
     pub fn overlap_with(&self, dst: &KeyRange) -> bool {
         // empty keyrange alaways overlaps
@@ -753,16 +921,18 @@ impl CompactStatus {
             tables: HashSet::new(),
         }
     }
-    fn compare_and_add(&mut self, cd: &CompactDef) -> Result<(), String> {
+    // compare_and_add holds the ranges and tables of cd, return false if they
+    // overlap with a compaction in progress
+    fn compare_and_add(&mut self, cd: &CompactDef) -> bool {
         {
             let this_level = &self.levels[cd.this_level as usize];
             let next_level = &self.levels[cd.next_level as usize];
 
             if this_level.overlap_with(&cd.this_range) {
-                return Err("not overlap".to_string());
+                return false;
             }
             if next_level.overlap_with(&cd.next_range) {
-                return Err("not overlap".to_string());
+                return false;
             }
         }
         {
@@ -777,17 +947,171 @@ impl CompactStatus {
         for id in &cd.tables {
             self.tables.insert(*id);
         }
-        Ok(())
+        true
+    }
+
+    // delete releases the ranges and tables held by a finished compaction
+    fn delete(&mut self, cd: &CompactDef) {
+        let this_level = &mut self.levels[cd.this_level as usize];
+        this_level.remove(&cd.this_range);
+        this_level.del_sz -= std::cmp::min(this_level.del_sz, cd.this_sz);
+
+        // a compaction inside a level holds the level range twice
+        self.levels[cd.next_level as usize].remove(&cd.next_range);
+        for id in &cd.tables {
+            self.tables.remove(id);
+        }
     }
 }
 
 impl LevelCompactStatus {
+    fn remove(&mut self, dst: &KeyRange) {
+        if let Some(i) = self
+            .ranges
+            .iter()
+            .position(|r| r.left == dst.left && r.right == dst.right)
+        {
+            self.ranges.remove(i);
+        }
+    }
+
     fn overlap_with(&self, dst: &KeyRange) -> bool {
         for r in &self.ranges {
-            if r.overlap_with(&dst) {
+            if r.overlap_with(dst) {
                 return true;
             }
         }
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::manifest::TableMeta;
+    use crate::utils::test_helper;
+
+    // add_table writes the entries to a new table in level 0 like a flush, an
+    // entry without a val is a tombstone
+    fn add_table(levels: &LevelManager, entries: &[(&str, u64, Option<&str>)]) {
+        let mut builder = TableBuilder::with_level(levels.opt.clone(), 0);
+        for &(key, seq, val) in entries {
+            let kind = match val {
+                Some(_) => ValueType::Value,
+                None => ValueType::Deletion,
+            };
+            let ikey = format::make_internal_key(key.as_bytes(), seq, kind);
+            builder.add(&ikey, val.unwrap_or_default().as_bytes());
+        }
+        let id = levels
+            .opt
+            .max_fid
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+        let name = file_helper::file_sstable_name(id);
+        let table = Table::open(levels.opt.clone(), name, Some(builder), 0).unwrap();
+        let meta = TableMeta {
+            id,
+            checksum: Vec::new(),
+        };
+        levels
+            .manifest_file
+            .write()
+            .unwrap()
+            .add_table_meta(0, meta)
+            .unwrap();
+        levels.levels[0].write().unwrap().add(table);
+    }
+
+    // compact_def makes an empty compaction of level 0 into next_level
+    fn compact_def(levels: &LevelManager, next_level: u32) -> CompactDef {
+        let t = levels.level_targets();
+        CompactDef {
+            compact_id: 0,
+            t: t.clone(),
+            this_level: 0,
+            p: CompactionPriority {
+                level: 0,
+                score: 0.0,
+                adjusted: 0.0,
+                t,
+            },
+            next_level,
+            this_sz: 0,
+            tables: Vec::new(),
+            splits: Vec::new(),
+            top: Vec::new(),
+            bot: Vec::new(),
+            this_range: KeyRange::new(),
+            next_range: KeyRange::new(),
+        }
+    }
+
+    // compact_l0_to_l0 compacts the tables of level 0 at top into level 0
+    async fn compact_l0_to_l0(levels: &LevelManager, top: Vec<u32>) {
+        let mut cd = compact_def(levels, 0);
+        {
+            let tables = &levels.levels[0].read().unwrap().tables;
+            for &i in &top {
+                cd.tables.push(tables[i as usize].id().unwrap());
+                cd.this_range
+                    .extend(KeyRange::with_table(&tables[i as usize]));
+            }
+        }
+        cd.top = top;
+        cd.next_range = cd.this_range.clone();
+        assert!(levels.compact_state.write().unwrap().compare_and_add(&cd));
+        levels.run_compact_def(&mut cd).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_l0_to_l0_tombstone() {
+        let opt = test_helper::test_options("compact_l0_to_l0_tombstone");
+        let levels = LevelManager::new(Arc::new(opt)).unwrap();
+        let get = |key: &str| {
+            let (kind, _) = levels.get(key.as_bytes(), format::MAX_SEQUENCE).unwrap()?;
+            Some(kind)
+        };
+
+        // the old version is in a table left out of the compaction, e.g. a
+        // table too large for it
+        add_table(&levels, &[("key", 1, Some("old"))]);
+        add_table(&levels, &[("a", 2, Some("a")), ("z", 2, Some("z"))]);
+        add_table(&levels, &[("a", 3, Some("a")), ("z", 3, Some("z"))]);
+        add_table(&levels, &[("key", 4, None)]);
+        compact_l0_to_l0(&levels, vec![1, 2, 3]).await;
+        assert_eq!(levels.get_level_num_tables(0), 2);
+        assert_eq!(get("key"), Some(ValueType::Deletion));
+
+        // a tombstone is dropped once no table out of the compaction holds the key
+        compact_l0_to_l0(&levels, vec![0, 1]).await;
+        assert_eq!(levels.get_level_num_tables(0), 1);
+        assert_eq!(get("key"), None);
+        assert_eq!(get("a"), Some(ValueType::Value));
+    }
+
+    #[tokio::test]
+    async fn test_l0_to_base_after_l0_to_l0() {
+        let opt = test_helper::test_options("compact_l0_to_base_after_l0_to_l0");
+        let levels = LevelManager::new(Arc::new(opt)).unwrap();
+        let get = |key: &str| {
+            let (_, val) = levels.get(key.as_bytes(), format::MAX_SEQUENCE).unwrap()?;
+            Some(val)
+        };
+
+        add_table(&levels, &[("k", 1, Some("old"))]);
+        add_table(&levels, &[("k", 5, Some("new"))]);
+        add_table(&levels, &[("x", 6, Some("x"))]);
+        // the output holds the oldest version of k under the newest id
+        compact_l0_to_l0(&levels, vec![0]).await;
+
+        // both versions of k move down, the table of x is left in level 0
+        let mut cd = compact_def(&levels, 1);
+        assert!(levels.fill_tables_l0_to_base(&mut cd));
+        levels.run_compact_def(&mut cd).await.unwrap();
+        assert_eq!(levels.get_level_num_tables(0), 1);
+        assert_eq!(levels.get_level_num_tables(1), 1);
+        assert_eq!(get("k"), Some(b"new".to_vec()));
+        assert_eq!(get("x"), Some(b"x".to_vec()));
+    }
+}
@@ -1,78 +1,454 @@
+use super::flush::{Flusher, MemTables};
+use super::format::ValueType;
+use super::iterator::{prefix_range, BoxedIterator, DBIter};
 use super::level::LevelManager;
 use super::memtable::MemTable;
-use super::options::Options;
-use crate::file::manifest::TableMeta;
-use crate::table::table::Table;
-use crate::table::table_builder::TableBuilder;
+use super::options::{Options, WalSyncMode, WriteOptions};
+use super::snapshot::Snapshot;
+use super::write_batch::WriteBatch;
+use crate::error::{Error, Result};
+use crate::file::wal::WalFile;
+use crate::table::table::BloomStats;
 use crate::utils::file::file_helper;
 use crate::utils::slice::Slice;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 
-pub(crate) struct DB {
-    mem_table: Option<MemTable>,
-    immu_mem_tables: Vec<MemTable>,
+pub struct DB {
+    mem_tables: Arc<RwLock<MemTables>>,
+    // writers wait in the queue, the writer at the front writes a group
+    write_queue: Mutex<WriteQueue>,
+    write_cond: Condvar,
+    // the leader of a group takes the lock, so seqs are assigned in the order
+    // batches are written to the memtable
+    write_lock: Arc<Mutex<SyncState>>,
+    flusher: Flusher,
+    // syncs the wal on a timer for WalSyncMode::Interval
+    wal_syncer: Mutex<Option<WalSyncer>>,
     levels: Arc<LevelManager>,
     opt: Arc<Options>,
+    // the seq of the last write, every write takes the next one
+    seq: AtomicU64,
+    // closer tells compactors to stop, writes fail once the db is closed
+    closer: watch::Sender<bool>,
+    closed: AtomicBool,
+    compactors: Mutex<Vec<JoinHandle<()>>>,
+    // broken wal records dropped by the recovery mode when the db opened
+    recovery_errors: Vec<Error>,
+}
+
+// a write waiting in the write queue
+struct PendingWrite {
+    id: u64,
+    batch: WriteBatch,
+    opt: WriteOptions,
+}
+
+// WriteQueue groups concurrent writes, the writer at the front leads a group
+// of the writes behind it, it writes them as a single wal record, syncs the wal
+// once for all of them if any of them asks, then hands the result to the others.
+// writes that skip the wal are not grouped with writes that do not
+#[derive(Default)]
+struct WriteQueue {
+    writes: VecDeque<PendingWrite>,
+    // results of the writes done by a leader, taken by their writers
+    results: HashMap<u64, Result<()>>,
+    leading: bool,
+    next_id: u64,
+}
+
+// the wal writes since the last sync
+struct SyncState {
+    last_sync: Instant,
+    unsynced: usize,
+}
+
+impl SyncState {
+    fn need_sync(&self, mode: WalSyncMode, record_size: usize) -> bool {
+        match mode {
+            WalSyncMode::NoSync => false,
+            WalSyncMode::EveryWrite => true,
+            WalSyncMode::Interval(interval) => self.last_sync.elapsed() >= interval,
+            WalSyncMode::Bytes(bytes) => (self.unsynced + record_size) as u64 >= bytes,
+        }
+    }
+
+    fn synced(&mut self) {
+        self.last_sync = Instant::now();
+        self.unsynced = 0;
+    }
+}
+
+// WalSyncer syncs the wal in the background once the oldest unsynced write
+// is as old as the interval, so a write is synced within the interval even if
+// no write comes after it. it stops when it is dropped
+struct WalSyncer {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl WalSyncer {
+    fn start(
+        interval: Duration,
+        state: Arc<Mutex<SyncState>>,
+        mem_tables: Arc<RwLock<MemTables>>,
+    ) -> Self {
+        let (stop, rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut wait = interval;
+            // the sender only disconnects, so a recv that does not time out stops
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(wait) {
+                // the write lock keeps the active memtable from being rotated
+                let mut state = state.lock().unwrap();
+                wait = interval;
+                if state.unsynced == 0 {
+                    continue;
+                }
+                let elapsed = state.last_sync.elapsed();
+                if elapsed < interval {
+                    wait = interval - elapsed;
+                    continue;
+                }
+                let mem_table = mem_tables.read().unwrap().mem_table.clone();
+                // a failed sync is retried after the interval, writes sync too
+                if mem_table.sync().is_ok() {
+                    state.synced();
+                }
+            }
+        });
+        WalSyncer {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for WalSyncer {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
 }
 
 impl DB {
-    pub fn open(opt: Arc<Options>) -> Result<Self, String> {
+    pub fn open(opt: Arc<Options>) -> Result<Self> {
+        opt.validate()?;
         let level_manager = Arc::new(LevelManager::new(opt.clone())?);
-        let mut db = DB {
-            mem_table: None,
-            immu_mem_tables: Vec::new(),
+        let (mem_tables, seq, recovery_errors) = Self::recovery(&opt, &level_manager)?;
+        let mem_tables = Arc::new(RwLock::new(mem_tables));
+        let flusher = Flusher::start(mem_tables.clone(), level_manager.clone(), opt.clone());
+        // flush memtables recovered from wal files
+        flusher.schedule();
+
+        let write_lock = Arc::new(Mutex::new(SyncState {
+            last_sync: Instant::now(),
+            unsynced: 0,
+        }));
+        let wal_syncer = match opt.wal_sync_mode {
+            WalSyncMode::Interval(interval) => Some(WalSyncer::start(
+                interval,
+                write_lock.clone(),
+                mem_tables.clone(),
+            )),
+            _ => None,
+        };
+
+        let db = DB {
+            mem_tables,
+            write_queue: Mutex::new(WriteQueue::default()),
+            write_cond: Condvar::new(),
+            write_lock,
+            flusher,
+            wal_syncer: Mutex::new(wal_syncer),
             levels: level_manager,
             opt,
+            seq: AtomicU64::new(seq),
+            closer: watch::channel(false).0,
+            closed: AtomicBool::new(false),
+            compactors: Mutex::new(Vec::new()),
+            recovery_errors,
         };
-
-        db.recovery().map_err(|e| e.to_string())?;
         Ok(db)
     }
 
-    pub fn set<T: AsRef<str>>(&mut self, key: T, val: T) -> Result<(), String> {
-        let key = key.as_ref().as_bytes();
-        let val = val.as_ref().as_bytes();
-        // check if memtable is full
+    pub fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, val: V) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, val);
+        self.write(batch)
+    }
+
+    // delete writes a tombstone for the key, it shadows the older vals
+    // in memtables and levels until compaction drops it in the bottommost level
+    pub fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    // get returns the val of the key, Ok(None) if the key is not found, and
+    // an error if a table that may hold it can not be read
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Slice>> {
+        let seq = self.seq.load(Ordering::Acquire);
+        match self.get_entry(key.as_ref(), seq)? {
+            Some((ValueType::Value, val)) => Ok(Some(val)),
+            Some((ValueType::Deletion, _)) | None => Ok(None),
+        }
+    }
+
+    // snapshot pins the current seq, reads at the snapshot keep seeing the db
+    // as it is now until the snapshot is dropped
+    pub fn snapshot(&self) -> Snapshot {
+        let seq = self.seq.load(Ordering::Acquire);
+        self.levels.snapshots.acquire(seq)
+    }
+
+    pub fn get_at<K: AsRef<[u8]>>(&self, snapshot: &Snapshot, key: K) -> Result<Option<Slice>> {
+        match self.get_entry(key.as_ref(), snapshot.seq())? {
+            Some((ValueType::Value, val)) => Ok(Some(val)),
+            Some((ValueType::Deletion, _)) | None => Ok(None),
+        }
+    }
+
+    // iter yields all keys and vals in ascending key order as of now, it can
+    // be reversed with rev
+    pub fn iter(&self) -> DBIter {
+        let seq = self.seq.load(Ordering::Acquire);
+        self.new_iter(seq, (Bound::Unbounded, Bound::Unbounded), None)
+    }
+
+    pub fn iter_at(&self, snapshot: &Snapshot) -> DBIter {
+        self.new_iter(snapshot.seq(), (Bound::Unbounded, Bound::Unbounded), None)
+    }
+
+    // range yields the keys in range, e.g. db.range("a".."c")
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> DBIter {
+        let seq = self.seq.load(Ordering::Acquire);
+        let start = range.start_bound().map(|k| k.as_ref().to_vec());
+        let end = range.end_bound().map(|k| k.as_ref().to_vec());
+        self.new_iter(seq, (start, end), None)
+    }
+
+    // prefix yields the keys that start with prefix, tables that can not hold
+    // the prefix are skipped
+    pub fn prefix<K: AsRef<[u8]>>(&self, prefix: K) -> DBIter {
+        let seq = self.seq.load(Ordering::Acquire);
+        let prefix = prefix.as_ref();
+        self.new_iter(seq, prefix_range(prefix), Some(prefix))
+    }
+
+    // new_iter merges iterators of memtables and all levels, the memtables and
+    // tables are held by the iterator, so flushes and compactions do not change
+    // what it sees
+    fn new_iter(
+        &self,
+        seq: u64,
+        range: (Bound<Slice>, Bound<Slice>),
+        prefix: Option<&[u8]>,
+    ) -> DBIter {
+        let mem_tables = self.mem_tables();
+        let tables = match prefix {
+            Some(prefix) => self.levels.prefix_tables(prefix),
+            None => self.levels.tables(),
+        };
+
+        let iterators = || {
+            let mut iters: Vec<BoxedIterator> = Vec::new();
+            for mem_table in &mem_tables {
+                iters.push(Box::new(mem_table.new_iterator()));
+            }
+            for table in &tables {
+                iters.push(Box::new(table.new_iterator()));
+            }
+            iters
+        };
+        DBIter::new(iterators(), iterators(), seq, range)
+    }
+
+    // write applies all entries in the batch atomically, the batch takes
+    // the next seqs and is written to a single memtable and wal record
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_opt(batch, &WriteOptions::default())
+    }
 
-        if let Some(mem_table) = &self.mem_table {
-            if mem_table.size() + key.len() + val.len() + 5 + 5 > self.opt.memtable_size as usize {
-                self.immu_mem_tables.push(self.mem_table.take().unwrap());
-                self.mem_table = Some(MemTable::new(self.opt.clone()).map_err(|e| e.to_string())?);
+    // write_opt is write with options, concurrent writes are committed in
+    // groups, a write returns after its group is written
+    pub fn write_opt(&self, batch: WriteBatch, opt: &WriteOptions) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if opt.sync && (opt.disable_wal || self.opt.disable_wal) {
+            return Err(Error::InvalidArgument(
+                "a write that skips the wal can not be synced".to_string(),
+            ));
+        }
+
+        let mut queue = self.write_queue.lock().unwrap();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.writes.push_back(PendingWrite {
+            id,
+            batch,
+            opt: *opt,
+        });
+        loop {
+            // a leader has written it
+            if let Some(res) = queue.results.remove(&id) {
+                return res;
+            }
+            if !queue.leading && queue.writes.front().is_some_and(|w| w.id == id) {
+                break;
             }
+            queue = self.write_cond.wait(queue).unwrap();
         }
-        if let Some(mem_table) = &mut self.mem_table {
-            mem_table.insert(key, val);
+        queue.leading = true;
+        drop(queue);
+
+        // the group is taken after the write lock, so the writes that queue up
+        // while waiting for it join the group
+        let mut sync_state = self.write_lock.lock().unwrap();
+        let (mut batch, opt, followers) = self.take_group();
+        let res = self.write_group(&mut sync_state, &mut batch, &opt);
+        drop(sync_state);
+
+        let mut queue = self.write_queue.lock().unwrap();
+        for id in followers {
+            queue.results.insert(id, res.clone());
         }
+        queue.leading = false;
+        self.write_cond.notify_all();
+        res
+    }
 
-        if self.immu_mem_tables.is_empty() == false {
-            let immu_mem_tables = std::mem::replace(&mut self.immu_mem_tables, Vec::new());
-            for immu_mem_table in immu_mem_tables {
-                let wal_name =
-                    file_helper::file_wal_name_with_dir(&self.opt.work_dir, immu_mem_table.id()?);
-                self.flush_memtable(immu_mem_table)?;
-                std::fs::remove_file(wal_name).map_err(|e| e.to_string())?;
+    // take_group pops the leader and the writes behind it from the queue and
+    // merges them into a batch no larger than a memtable, unless the leader is,
+    // returns the batch, the options of the group and the ids of the followers
+    fn take_group(&self) -> (WriteBatch, WriteOptions, Vec<u64>) {
+        let mut queue = self.write_queue.lock().unwrap();
+        let leader = queue.writes.pop_front().unwrap();
+        let (mut batch, mut opt) = (leader.batch, leader.opt);
+        let mut followers = Vec::new();
+        while let Some(w) = queue.writes.front() {
+            if batch.data().len() + w.batch.data().len() > self.opt.memtable_size as usize
+                || w.opt.disable_wal != opt.disable_wal
+            {
+                break;
             }
+            let w = queue.writes.pop_front().unwrap();
+            batch.append(&w.batch);
+            opt.sync |= w.opt.sync;
+            followers.push(w.id);
         }
+        (batch, opt, followers)
+    }
 
-        Ok(())
+    // write_group writes the batch of a group, it is called with the write lock held
+    fn write_group(
+        &self,
+        state: &mut SyncState,
+        batch: &mut WriteBatch,
+        opt: &WriteOptions,
+    ) -> Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        let record_size = WalFile::record_size(batch.data());
+        let mem_table = self.make_room_for_write(state, record_size)?;
+        let disable_wal = opt.disable_wal || self.opt.disable_wal;
+        let opt = WriteOptions {
+            sync: !disable_wal
+                && (opt.sync || state.need_sync(self.opt.wal_sync_mode, record_size)),
+            disable_wal,
+        };
+
+        let last_seq = self.seq.load(Ordering::Acquire);
+        batch.set_seq(last_seq + 1);
+        let res = mem_table.write(batch, &opt);
+        // publish the entries to readers after the whole batch is in the memtable,
+        // the seqs are taken even if the write fails, as the record may be in wal
+        self.seq
+            .store(last_seq + batch.len() as u64, Ordering::Release);
+
+        if opt.sync && res.is_ok() {
+            state.synced();
+        } else if !disable_wal {
+            state.unsynced += record_size;
+        }
+        res
     }
 
-    pub fn get<T: AsRef<str>>(&self, key: T) -> Option<Slice> {
-        let key = key.as_ref().as_bytes();
+    // make_room_for_write rotates the memtable if the record does not fit in it,
+    // and returns the memtable to write, it is called with the write lock held.
+    // the wal of the rotated memtable is synced unless the sync mode is NoSync,
+    // then the memtable is flushed in background, writes stall while there are
+    // too many immu memtables
+    fn make_room_for_write(
+        &self,
+        state: &mut SyncState,
+        record_size: usize,
+    ) -> Result<Arc<MemTable>> {
+        let mem_table = self.mem_tables.read().unwrap().mem_table.clone();
+        // a record larger than a memtable goes to an empty memtable
+        if mem_table.size() == 0
+            || mem_table.size() + record_size <= self.opt.memtable_size as usize
+        {
+            return Ok(mem_table);
+        }
 
-        if let Some(mem_table) = &self.mem_table {
-            if let Some(val) = mem_table.seek(key) {
-                return Some(val);
-            }
+        self.rotate_mem_table(state, &mem_table)
+    }
+
+    // rotate_mem_table turns the active memtable into an immu memtable and
+    // schedules a flush, it is called with the write lock held
+    fn rotate_mem_table(
+        &self,
+        state: &mut SyncState,
+        mem_table: &Arc<MemTable>,
+    ) -> Result<Arc<MemTable>> {
+        if self.opt.wal_sync_mode != WalSyncMode::NoSync && state.unsynced > 0 {
+            mem_table.sync()?;
+            state.synced();
+        }
+        let max_immu = self.opt.max_immu_mem_tables as usize;
+        self.flusher.wait(max_immu.saturating_sub(1))?;
+        let new_mem_table = Arc::new(MemTable::new(self.opt.clone())?);
+        {
+            let mut mem_tables = self.mem_tables.write().unwrap();
+            let old = std::mem::replace(&mut mem_tables.mem_table, new_mem_table.clone());
+            mem_tables.immu_mem_tables.push(old);
         }
+        self.flusher.schedule();
+        Ok(new_mem_table)
+    }
+
+    // memtables from the newest to the oldest
+    fn mem_tables(&self) -> Vec<Arc<MemTable>> {
+        let mem_tables = self.mem_tables.read().unwrap();
+        let mut v = vec![mem_tables.mem_table.clone()];
+        v.extend(mem_tables.immu_mem_tables.iter().rev().cloned());
+        v
+    }
 
-        for immu_mem_table in &self.immu_mem_tables {
-            if let Some(val) = immu_mem_table.seek(key) {
-                return Some(val);
+    // get_entry returns the newest entry of the key that is visible at seq,
+    // which may be a tombstone
+    fn get_entry(&self, key: &[u8], seq: u64) -> Result<Option<(ValueType, Slice)>> {
+        // memtables are searched from the newest to the oldest, the first
+        // entry found is the newest one
+        for mem_table in self.mem_tables() {
+            if let Some(entry) = mem_table.seek(key, seq) {
+                return Ok(Some(entry));
             }
         }
 
-        self.levels.get(key)
+        self.levels.get(key, seq)
     }
 
     // debug!
@@ -80,93 +456,1560 @@ impl DB {
     pub async fn start_compacter(&self) {
         let num = self.opt.num_compactors;
 
+        let mut compactors = self.compactors.lock().unwrap();
+        if self.closed.load(Ordering::Acquire) {
+            return;
+        }
         for i in 0..num {
             let levels = self.levels.clone();
-            tokio::spawn(async move {
-                levels.run_compacter(i).await;
-            });
+            let closer = self.closer.subscribe();
+            compactors.push(tokio::spawn(async move {
+                levels.run_compacter(i, closer).await;
+            }));
+        }
+    }
+
+    // compact_range flushes the memtables, then compacts the tables that hold
+    // user keys in [start, end] down to the bottommost level, so tombstones and
+    // overwritten vals in the range are dropped unless a snapshot sees them.
+    // a missing bound is unbounded, it returns when the compaction is done
+    pub async fn compact_range<K: AsRef<[u8]>>(
+        &self,
+        start: Option<K>,
+        end: Option<K>,
+    ) -> Result<()> {
+        self.flush(false)?;
+        self.flusher.wait_async(0).await?;
+        let start = start.as_ref().map(|k| k.as_ref());
+        let end = end.as_ref().map(|k| k.as_ref());
+        self.levels.compact_range(start, end).await
+    }
+
+    // close stops compactors after the compactions in progress finish, waits
+    // for immu memtables to be flushed, then syncs the wal of the active
+    // memtable and the manifest. writes fail after the db is closed. every
+    // step runs even if one before it fails, the first error is returned
+    pub async fn close(&self) -> Result<()> {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let _ = self.closer.send(true);
+        let mut res = Ok(());
+        let compactors = std::mem::take(&mut *self.compactors.lock().unwrap());
+        for compactor in compactors {
+            let r = compactor
+                .await
+                .map_err(|e| Error::Background(e.to_string()));
+            res = res.and(r);
+        }
+
+        // wait for the writes in progress, the writes after them fail as the
+        // db is closed, so no memtable is rotated after the lock. the lock
+        // blocks, so it is waited for out of the runtime
+        let write_lock = self.write_lock.clone();
+        let r = tokio::task::spawn_blocking(move || drop(write_lock.lock().unwrap()))
+            .await
+            .map_err(|e| Error::Background(e.to_string()));
+        res = res.and(r);
+        let r = self.flusher.wait_async(0).await;
+        res = res.and(r);
+
+        // the wal is synced below, stop syncing it in the background, the
+        // syncer thread is joined and the files synced out of the runtime
+        let wal_syncer = self.wal_syncer.lock().unwrap().take();
+        let mem_tables = self.mem_tables.clone();
+        let levels = self.levels.clone();
+        let r = tokio::task::spawn_blocking(move || {
+            drop(wal_syncer);
+            Self::sync_files(&mem_tables, &levels)
+        })
+        .await
+        .map_err(|e| Error::Background(e.to_string()))
+        .and_then(|r| r);
+        res.and(r)
+    }
+
+    // flush rotates the active memtable, the flusher writes it to a table in
+    // level 0, records the table in the manifest and removes its wal. if wait
+    // is true, flush returns after all immu memtables are flushed, so writes
+    // that skip the wal are persisted
+    pub fn flush(&self, wait: bool) -> Result<()> {
+        {
+            let mut state = self.write_lock.lock().unwrap();
+            if self.closed.load(Ordering::Acquire) {
+                return Err(Error::Closed);
+            }
+            let mem_table = self.mem_tables.read().unwrap().mem_table.clone();
+            if mem_table.size() != 0 {
+                self.rotate_mem_table(&mut state, &mem_table)?;
+            }
         }
+        if wait {
+            self.flusher.wait(0)?;
+        }
+        Ok(())
+    }
+
+    // bloom_stats counts how the bloom filters of tables in the level answer
+    // point lookups since the db opened, none if the level is out of
+    // max_level_num
+    pub fn bloom_stats(&self, level: u32) -> Option<&BloomStats> {
+        self.levels.bloom_stats.get(level as usize)
+    }
+
+    // wal_recovery_errors returns the corruption errors of the broken wal
+    // records that wal_recovery_mode dropped when the db opened
+    pub fn wal_recovery_errors(&self) -> &[Error] {
+        &self.recovery_errors
+    }
+
+    fn sync(&self) -> Result<()> {
+        Self::sync_files(&self.mem_tables, &self.levels)
+    }
+
+    fn sync_files(mem_tables: &RwLock<MemTables>, levels: &LevelManager) -> Result<()> {
+        mem_tables.read().unwrap().mem_table.sync()?;
+        levels.manifest_file.read().unwrap().sync()
     }
 
-    fn recovery(&mut self) -> std::io::Result<()> {
+    // recovery replays wal files left in work dir to immu memtables, and returns
+    // memtables, the largest seq that has been written and the errors of the
+    // broken records dropped
+    fn recovery(opt: &Arc<Options>, levels: &LevelManager) -> Result<(MemTables, u64, Vec<Error>)> {
         let mut fids = Vec::new();
-        for entry in std::fs::read_dir(&self.opt.work_dir)? {
+        for entry in std::fs::read_dir(&opt.work_dir)? {
             let entry = entry?;
             if entry.file_type()?.is_dir() {
                 continue;
             }
 
-            if let Ok(id) = file_helper::fid_wal(entry.file_name().to_str().unwrap()) {
+            if let Some(Ok(id)) = entry.file_name().to_str().map(file_helper::fid_wal) {
                 // consider the existence of the wal file and update max_fid
-                let max_fid = self.opt.max_fid.load(std::sync::atomic::Ordering::Relaxed);
+                let max_fid = opt.max_fid.load(std::sync::atomic::Ordering::Relaxed);
                 if max_fid < id {
-                    self.opt
-                        .max_fid
-                        .store(id, std::sync::atomic::Ordering::Relaxed);
+                    opt.max_fid.store(id, std::sync::atomic::Ordering::Relaxed);
                 }
                 fids.push(id);
             }
         }
         fids.sort();
 
+        // continue from the largest seq that has been written
+        let mut max_seq = levels.max_seq();
+        let mut immu_mem_tables = Vec::new();
+        let mut errors = Vec::new();
         for fid in fids {
-            let mem = MemTable::open(self.opt.clone(), fid)?;
-            self.immu_mem_tables.push(mem);
+            let (mem, dropped) = MemTable::open(opt.clone(), fid)?;
+            errors.extend(dropped);
+            max_seq = std::cmp::max(max_seq, mem.max_seq());
+            immu_mem_tables.push(Arc::new(mem));
         }
 
-        self.mem_table = Some(MemTable::new(self.opt.clone())?);
+        let mem_tables = MemTables {
+            mem_table: Arc::new(MemTable::new(opt.clone())?),
+            immu_mem_tables,
+        };
+        Ok((mem_tables, max_seq, errors))
+    }
+}
 
-        Ok(())
+// dropping a db that is not closed aborts compactors, a compaction in progress
+// leaves nothing in the manifest, then the wal and the manifest are synced
+impl Drop for DB {
+    fn drop(&mut self) {
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let _ = self.closer.send(true);
+        for compactor in self.compactors.get_mut().unwrap().drain(..) {
+            compactor.abort();
+        }
+        // drop can not return the error, close returns it
+        let _ = self.sync();
     }
+}
 
-    fn flush_memtable(&mut self, immu_mem_table: MemTable) -> Result<(), String> {
-        // alloc a fid
-        let fid = immu_mem_table.id()?;
-        let sst_name = file_helper::file_sstable_name(fid);
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::db::format;
+    use crate::db::options::{Compression, PrefixExtractor, WalRecoveryMode};
+    use crate::file::wal::RECORD_HEADER_SIZE;
+    use crate::utils::test_helper;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    #[tokio::test]
+    async fn test_db_start() {
+        let opt = test_helper::test_options("db_start");
+        let db = DB::open(Arc::new(opt)).unwrap();
+        db.start_compacter().await;
+
+        let v = test_helper::generate_incredible_strings(1000);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_db_delete() {
+        let opt = test_helper::test_options("db_delete");
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        // the first keys have been flushed to level 0
+        for x in &v[..100] {
+            db.delete(x).unwrap();
+        }
+        // flush the tombstones too
+        for x in &v[200..] {
+            db.set(x, x).unwrap();
+        }
+
+        for x in &v[..100] {
+            assert_eq!(db.get(x).unwrap(), None);
+        }
+        for x in &v[100..] {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+
+        // a key can be set again after it is deleted
+        db.set(&v[0], &v[1]).unwrap();
+        assert_eq!(db.get(&v[0]).unwrap(), Some(v[1].as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_db_empty_value() {
+        let opt = test_helper::test_options("db_empty_value");
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        db.set("key", "").unwrap();
+        assert_eq!(db.get("key").unwrap(), Some(Vec::new()));
+
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        assert_eq!(db.get("key").unwrap(), Some(Vec::new()));
+
+        db.delete("key").unwrap();
+        assert_eq!(db.get("key").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_db_compact() {
+        let opt = test_helper::test_options("db_compact");
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let v = test_helper::generate_incredible_strings(1000);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        // overwrite and delete keys, the newer versions are in newer tables
+        for x in v.iter().step_by(3) {
+            db.set(x.as_str(), "new").unwrap();
+        }
+        for x in v.iter().step_by(5) {
+            db.delete(x).unwrap();
+        }
 
-        let mut table_builder = TableBuilder::new(self.opt.clone());
-        for entry in immu_mem_table.skiplist.iter() {
-            let (key, val) = (entry.key(), entry.value());
+        let check = |db: &DB| {
+            for (i, x) in v.iter().enumerate() {
+                let expect = if i % 5 == 0 {
+                    None
+                } else if i % 3 == 0 {
+                    Some("new".as_bytes().to_vec())
+                } else {
+                    Some(x.as_bytes().to_vec())
+                };
+                assert_eq!(db.get(x).unwrap(), expect, "key {}", x);
+            }
+        };
+        db.flusher.wait(0).unwrap();
+        check(&db);
 
-            table_builder.add(key, val);
+        // compact level 0 to the base level
+        while db.levels.get_level_num_tables(0) >= db.opt.num_level_zero_tables {
+            db.levels.run_once(0).await.unwrap();
+            check(&db);
         }
+    }
 
-        // create a table
+    #[tokio::test]
+    async fn test_db_compact_range() {
+        let opt = test_helper::test_options("db_compact_range");
+        let db = DB::open(Arc::new(opt)).unwrap();
 
-        let table = Table::open(self.opt.clone(), sst_name, Some(table_builder))
-            .map_err(|e| e.to_string())?;
-        let mut manifest_file = self.levels.manifest_file.write().unwrap();
+        let v = test_helper::generate_incredible_strings(1000);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        for x in v.iter().step_by(2) {
+            db.delete(x).unwrap();
+        }
 
-        manifest_file.add_table_meta(
-            0,
-            TableMeta {
-                id: fid,
-                checksum: "bupt".as_bytes().to_vec(),
+        let bottom = db.levels.levels.len() - 1;
+        // the entries in tables, tombstones and older versions included
+        let entries = |db: &DB| {
+            let mut n = 0;
+            for table in db.levels.tables() {
+                let mut iter = table.new_iterator();
+                iter.seek_to_first();
+                while iter.valid() {
+                    n += 1;
+                    iter.next();
+                }
+            }
+            n
+        };
+
+        db.compact_range(Some(&v[0]), Some(&v[499])).await.unwrap();
+        for i in 0..bottom {
+            let tables = &db.levels.levels[i].read().unwrap().tables;
+            assert!(tables
+                .iter()
+                .all(|t| format::user_key(t.min_key()) > v[499].as_bytes()));
+        }
+        for (i, x) in v.iter().enumerate() {
+            let expect = (i % 2 == 1).then(|| x.as_bytes().to_vec());
+            assert_eq!(db.get(x).unwrap(), expect);
+        }
+
+        db.compact_range(None::<&str>, None).await.unwrap();
+        for i in 0..bottom {
+            assert_eq!(db.levels.get_level_num_tables(i), 0);
+        }
+        // only the live entries are left in the bottommost level
+        assert_eq!(entries(&db), v.len() / 2);
+        for (i, x) in v.iter().enumerate() {
+            let expect = (i % 2 == 1).then(|| x.as_bytes().to_vec());
+            assert_eq!(db.get(x).unwrap(), expect);
+        }
+        assert_eq!(db.iter().count(), v.len() / 2);
+    }
+
+    #[tokio::test]
+    async fn test_db_compression() {
+        let mut opt = test_helper::test_options("db_compression");
+        opt.compression = Compression::Zstd(3);
+        opt.compression_per_level = vec![Compression::None, Compression::Snappy];
+        let opt = Arc::new(opt);
+        let db = DB::open(opt.clone()).unwrap();
+
+        let v = test_helper::generate_incredible_strings(500);
+        let val = |x: &str| format!("{{\"key\": \"{}\", \"val\": \"{}\"}}", x, x.repeat(4));
+        for x in &v {
+            db.set(x, val(x)).unwrap();
+        }
+        db.compact_range(None::<&str>, None).await.unwrap();
+        drop(db);
+
+        let db = DB::open(opt).unwrap();
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(val(x).into_bytes()));
+        }
+        assert_eq!(db.iter().count(), v.len());
+    }
+
+    #[test]
+    fn test_db_prefix_bloom() {
+        let mut opt = test_helper::test_options("db_prefix_bloom");
+        opt.bloom_false_positive = 0.01;
+        let extractor = PrefixExtractor::Delimited(b'/', 2);
+        assert_eq!(extractor.prefix(b"t1/e3/k4"), Some("t1/e3/".as_bytes()));
+        assert_eq!(extractor.prefix(b"t1/e3"), None);
+        assert_eq!(PrefixExtractor::Fixed(3).prefix(b"t1"), None);
+        opt.prefix_extractor = Some(extractor);
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        // tenant 1 has no entity 5
+        let mut keys = Vec::new();
+        for i in 0..4 {
+            for j in (0..10).filter(|&j| i != 1 || j != 5) {
+                for k in 0..10 {
+                    keys.push(format!("t{}/e{}/k{}", i, j, k));
+                }
+            }
+        }
+        for key in &keys {
+            db.set(key, key).unwrap();
+        }
+        db.flush(true).unwrap();
+        let num_tables = db.levels.tables().len();
+        assert!(num_tables > 10);
+
+        // the tables of other tenants are out of the key range, and the filter
+        // skips the tables of tenant 1 that do not hold the entity
+        assert!(db.levels.prefix_tables(b"t1/e3/").len() < num_tables / 2);
+        assert_eq!(db.levels.prefix_tables(b"t1/e5/").len(), 0);
+        assert_eq!(db.prefix("t1/e5/").count(), 0);
+        // a prefix shorter than the extracted one only uses the key range
+        let tables = db.levels.prefix_tables(b"t1/");
+        assert!(tables.len() > 1 && tables.len() < num_tables);
+
+        let expected = |prefix: &str| {
+            keys.iter()
+                .filter(|key| key.starts_with(prefix))
+                .map(|key| (key.clone().into_bytes(), key.clone().into_bytes()))
+                .collect::<Vec<_>>()
+        };
+        for prefix in ["t1/e3/", "t1/e3/k4", "t1/", "t2/e9/"] {
+            let mut want = expected(prefix);
+            want.sort();
+            assert_eq!(db.prefix(prefix).collect::<Vec<_>>(), want);
+        }
+        drop(db);
+
+        // filters of another extractor are not used
+        let mut opt = Options::test_new();
+        opt.work_dir = format!("{}/db_prefix_bloom", opt.work_dir);
+        opt.prefix_extractor = Some(PrefixExtractor::Fixed(3));
+        let db = DB::open(Arc::new(opt)).unwrap();
+        assert_eq!(db.prefix("t1/e3/").count(), 10);
+        assert_eq!(db.prefix("t1/").count(), 90);
+    }
+
+    #[test]
+    fn test_db_bloom_lookup() {
+        let mut opt = test_helper::test_options("db_bloom_lookup");
+        opt.bloom_false_positive = 0.01;
+        let opt = Arc::new(opt);
+        let db = DB::open(opt.clone()).unwrap();
+
+        let v = test_helper::generate_incredible_strings(500);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        db.flush(true).unwrap();
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+        let stats = db.bloom_stats(0).unwrap();
+        assert_eq!(stats.true_positive(), v.len() as u64);
+
+        // a missing key reads no block unless the filter lets it through, a
+        // key out of the range of all tables is not looked up in any filter
+        let cache = opt.block_cache.as_ref().unwrap();
+        for x in &v {
+            let missing = format!("{}-", x);
+            let reads = cache.hits() + cache.misses();
+            let false_positive = stats.false_positive();
+            assert_eq!(db.get(&missing).unwrap(), None);
+            if stats.false_positive() == false_positive {
+                assert_eq!(cache.hits() + cache.misses(), reads);
+            }
+        }
+        assert!(stats.useful() + stats.false_positive() <= v.len() as u64);
+        assert!(stats.useful() > v.len() as u64 * 9 / 10);
+        for level in 1..opt.max_level_num {
+            assert_eq!(db.bloom_stats(level).unwrap().useful(), 0);
+        }
+        assert!(db.bloom_stats(opt.max_level_num).is_none());
+    }
+
+    #[test]
+    fn test_db_l0_newest_first() {
+        let mut opt = test_helper::test_options("db_l0_newest_first");
+        opt.bloom_false_positive = 0.01;
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        // every table in level 0 holds a version of the key
+        for i in 0..5 {
+            db.set("key", format!("val{}", i)).unwrap();
+            db.set(format!("key{}", i), "").unwrap();
+            db.flush(true).unwrap();
+        }
+        assert_eq!(db.levels.get_level_num_tables(0), 5);
+        assert_eq!(db.get("key").unwrap(), Some("val4".as_bytes().to_vec()));
+        // the newest table holds the newest version, older tables are not read
+        let stats = db.bloom_stats(0).unwrap();
+        assert_eq!(stats.true_positive() + stats.false_positive(), 1);
+    }
+
+    // test_db_model applies random writes to the db and to a BTreeMap, and
+    // compares them across flushes and compactions
+    #[tokio::test]
+    async fn test_db_model() {
+        let mut opt = test_helper::test_options("db_model");
+        opt.num_level_zero_tables = 4;
+        opt.bloom_false_positive = 0.01;
+        let opt = Arc::new(opt);
+        let mut db = DB::open(opt.clone()).unwrap();
+
+        // a fixed seed keeps the test reproducible, CKV_MODEL_SEED runs it
+        // with another seed
+        let seed = std::env::var("CKV_MODEL_SEED")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(23);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut model = std::collections::BTreeMap::new();
+        let keys: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .collect();
+
+        for round in 0..20 {
+            for op in 0..300 {
+                let key = keys.choose(&mut rng).unwrap().clone();
+                if rng.gen_bool(0.8) {
+                    let val = format!("{}-{}", round, op).into_bytes();
+                    db.set(&key, &val).unwrap();
+                    model.insert(key, val);
+                } else {
+                    db.delete(&key).unwrap();
+                    model.remove(&key);
+                }
+            }
+            match rng.gen_range(0..4) {
+                0 => db.flush(true).unwrap(),
+                1 => {
+                    db.flush(true).unwrap();
+                    while db.levels.get_level_num_tables(0) >= opt.num_level_zero_tables {
+                        db.levels.run_once(0).await.unwrap();
+                    }
+                }
+                2 => {
+                    let mut range = [keys.choose(&mut rng), keys.choose(&mut rng)];
+                    range.sort();
+                    db.compact_range(range[0], range[1]).await.unwrap();
+                }
+                _ => {
+                    // replay the wal of the memtables
+                    drop(db);
+                    db = DB::open(opt.clone()).unwrap();
+                }
+            }
+
+            for key in &keys {
+                assert_eq!(
+                    db.get(key).unwrap().as_ref(),
+                    model.get(key),
+                    "seed {}",
+                    seed
+                );
+            }
+            let expect: Vec<_> = model.clone().into_iter().collect();
+            assert_eq!(db.iter().collect::<Vec<_>>(), expect, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_db_snapshot() {
+        let opt = test_helper::test_options("db_snapshot");
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        let snapshot = db.snapshot();
+
+        // overwrite and delete after the snapshot, and flush them to level 0
+        for x in &v[..100] {
+            db.set(x.as_str(), "new").unwrap();
+        }
+        for x in &v[100..200] {
+            db.delete(x).unwrap();
+        }
+        db.set("zzz", "new").unwrap();
+
+        for x in &v {
+            assert_eq!(
+                db.get_at(&snapshot, x).unwrap(),
+                Some(x.as_bytes().to_vec())
+            );
+        }
+        assert_eq!(db.get_at(&snapshot, "zzz").unwrap(), None);
+        assert_eq!(db.get(&v[0]).unwrap(), Some("new".as_bytes().to_vec()));
+        assert_eq!(db.get(&v[100]).unwrap(), None);
+
+        let mut sorted = v.clone();
+        sorted.sort();
+        let old: Vec<Slice> = db.iter_at(&snapshot).map(|(k, _)| k).collect();
+        let old_expect: Vec<Slice> = sorted.iter().map(|x| x.as_bytes().to_vec()).collect();
+        assert_eq!(old, old_expect);
+
+        let now: Vec<(Slice, Slice)> = db.iter().collect();
+        assert_eq!(now.len(), 300 - 100 + 1);
+        for (k, val) in &now {
+            assert_eq!(
+                db.get(String::from_utf8(k.clone()).unwrap()).unwrap(),
+                Some(val.clone())
+            );
+        }
+        assert!(now.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[tokio::test]
+    async fn test_db_snapshot_compact() {
+        let opt = test_helper::test_options("db_snapshot_compact");
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let v = test_helper::generate_incredible_strings(1000);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        let snapshot = db.snapshot();
+        for x in v.iter().step_by(2) {
+            db.delete(x).unwrap();
+        }
+        for x in v.iter().skip(1).step_by(2) {
+            db.set(x.as_str(), "new").unwrap();
+        }
+
+        // compaction keeps the versions the snapshot sees
+        db.flusher.wait(0).unwrap();
+        assert!(db.levels.get_level_num_tables(0) >= db.opt.num_level_zero_tables);
+        while db.levels.get_level_num_tables(0) >= db.opt.num_level_zero_tables {
+            db.levels.run_once(0).await.unwrap();
+        }
+        for (i, x) in v.iter().enumerate() {
+            assert_eq!(
+                db.get_at(&snapshot, x).unwrap(),
+                Some(x.as_bytes().to_vec())
+            );
+            let expect = if i % 2 == 0 {
+                None
+            } else {
+                Some("new".as_bytes().to_vec())
+            };
+            assert_eq!(db.get(x).unwrap(), expect);
+        }
+        assert_eq!(db.levels.snapshots.seqs(), vec![snapshot.seq()]);
+
+        drop(snapshot);
+        assert!(db.levels.snapshots.seqs().is_empty());
+    }
+
+    #[test]
+    fn test_db_wal_recovery() {
+        // write three batches to the wal and break the 2nd one
+        let setup = |name: &str, mode: WalRecoveryMode| {
+            let mut opt = test_helper::test_options(name);
+            opt.wal_recovery_mode = mode;
+            let opt = Arc::new(opt);
+            let db = DB::open(opt.clone()).unwrap();
+            let mut offsets = Vec::new();
+            for key in ["a", "b", "c"] {
+                offsets.push(db.mem_tables.read().unwrap().mem_table.size());
+                db.set(key, key).unwrap();
+            }
+            let fid = db.mem_tables.read().unwrap().mem_table.id();
+            drop(db);
+
+            let wal_name = file_helper::file_wal_name_with_dir(&opt.work_dir, fid);
+            let mut wal = std::fs::read(&wal_name).unwrap();
+            wal[offsets[1] + RECORD_HEADER_SIZE] ^= 0xff;
+            std::fs::write(&wal_name, wal).unwrap();
+            (opt, file_helper::file_wal_name(fid), offsets[1])
+        };
+
+        let (opt, wal_name, offset) = setup("db_wal_fail", WalRecoveryMode::FailOnBadRecord);
+        match DB::open(opt.clone()).err() {
+            Some(Error::Corruption {
+                file, offset: o, ..
+            }) => {
+                assert_eq!(file, wal_name);
+                assert_eq!(o, offset as u64);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+        // the wal is kept, so it can be opened with another mode
+        let mut opt = Arc::into_inner(opt).unwrap();
+        opt.wal_recovery_mode = WalRecoveryMode::SkipBadRecords;
+        let db = DB::open(Arc::new(opt)).unwrap();
+        assert_eq!(db.get("c").unwrap(), Some("c".as_bytes().to_vec()));
+        // the dropped record is reported, not printed
+        match db.wal_recovery_errors() {
+            [Error::Corruption {
+                file, offset: o, ..
+            }] => {
+                assert_eq!(file, &wal_name);
+                assert_eq!(*o, offset as u64);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let (opt, _, _) = setup("db_wal_skip", WalRecoveryMode::SkipBadRecords);
+        let db = DB::open(opt).unwrap();
+        assert_eq!(db.wal_recovery_errors().len(), 1);
+        assert_eq!(db.get("a").unwrap(), Some("a".as_bytes().to_vec()));
+        assert_eq!(db.get("b").unwrap(), None);
+        assert_eq!(db.get("c").unwrap(), Some("c".as_bytes().to_vec()));
+
+        let (opt, _, _) = setup("db_wal_truncate", WalRecoveryMode::TruncateAtBadRecord);
+        let db = DB::open(opt).unwrap();
+        assert_eq!(db.wal_recovery_errors().len(), 1);
+        assert_eq!(db.get("a").unwrap(), Some("a".as_bytes().to_vec()));
+        assert_eq!(db.get("b").unwrap(), None);
+        assert_eq!(db.get("c").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_binary() {
+        let opt = Arc::new(test_helper::test_options("db_binary"));
+        let db = DB::open(opt.clone()).unwrap();
+
+        // keys and vals that are not utf-8 and hold zero bytes
+        let mut model = std::collections::BTreeMap::new();
+        for i in 0..600u32 {
+            let key = vec![0xff, 0, (i >> 8) as u8, i as u8, 0];
+            let val = vec![0, 0xc3, i as u8, 0];
+            db.set(&key, &val).unwrap();
+            model.insert(key, val);
+        }
+        for key in [vec![0], vec![0, 0], vec![0x80]] {
+            db.set(&key, [0u8; 3]).unwrap();
+            model.insert(key, vec![0; 3]);
+        }
+        db.delete([0xff, 0, 0, 1, 0]).unwrap();
+        model.remove([0xff, 0, 0, 1, 0].as_slice());
+
+        // a key is not equal to its prefix padded with zero bytes
+        assert_eq!(db.get([0]).unwrap(), Some(vec![0; 3]));
+        assert_eq!(db.get([0, 0]).unwrap(), Some(vec![0; 3]));
+        assert_eq!(db.get([0, 0, 0]).unwrap(), None);
+        assert_eq!(db.get([0xff, 0, 0, 1, 0]).unwrap(), None);
+
+        let expect: Vec<_> = model.clone().into_iter().collect();
+        assert_eq!(db.iter().collect::<Vec<_>>(), expect);
+        assert_eq!(
+            db.prefix([0xff, 0]).count(),
+            model.keys().filter(|k| k.starts_with(&[0xff, 0])).count()
+        );
+
+        // the same keys come back from the wal and the tables after reopening
+        db.flusher.wait(0).unwrap();
+        drop(db);
+        let db = DB::open(opt).unwrap();
+        for (key, val) in &model {
+            assert_eq!(db.get(key).unwrap().as_ref(), Some(val));
+        }
+        assert_eq!(db.iter().rev().collect::<Vec<_>>(), {
+            let mut expect = expect;
+            expect.reverse();
+            expect
+        });
+    }
+
+    #[test]
+    fn test_db_write_batch() {
+        let opt = Arc::new(test_helper::test_options("db_write_batch"));
+        let db = DB::open(opt.clone()).unwrap();
+
+        db.set("a", "0").unwrap();
+        let mut batch1 = WriteBatch::new();
+        batch1.put("a", "1");
+        batch1.put("b", "1");
+        batch1.delete("c");
+        db.set("c", "0").unwrap();
+        let seq = db.seq.load(Ordering::Acquire);
+        db.write(batch1.clone()).unwrap();
+        // the batch takes a seq range, the later entry wins
+        assert_eq!(db.seq.load(Ordering::Acquire), seq + 3);
+        assert_eq!(db.get("a").unwrap(), Some("1".as_bytes().to_vec()));
+        assert_eq!(db.get("b").unwrap(), Some("1".as_bytes().to_vec()));
+        assert_eq!(db.get("c").unwrap(), None);
+
+        let mut batch2 = WriteBatch::new();
+        batch2.put("d", "2");
+        batch2.delete("a");
+        db.write(batch2.clone()).unwrap();
+        assert_eq!(db.get("d").unwrap(), Some("2".as_bytes().to_vec()));
+        assert_eq!(db.get("a").unwrap(), None);
+
+        // break the last batch in wal file, it is dropped as a whole when replaying
+        let wal_name = file_helper::file_wal_name_with_dir(
+            &opt.work_dir,
+            db.mem_tables.read().unwrap().mem_table.id(),
+        );
+        drop(db);
+        let mut wal = std::fs::read(&wal_name).unwrap();
+        // the last written byte is the key of the delete in batch2
+        let last = wal.iter().rposition(|&b| b != 0).unwrap();
+        assert_eq!(wal[last], b'a');
+        wal[last] ^= 0xff;
+        std::fs::write(&wal_name, wal).unwrap();
+
+        let db = DB::open(opt).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".as_bytes().to_vec()));
+        assert_eq!(db.get("b").unwrap(), Some("1".as_bytes().to_vec()));
+        assert_eq!(db.get("c").unwrap(), None);
+        assert_eq!(db.get("d").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_large_value() {
+        let opt = Arc::new(test_helper::test_options("db_large_value"));
+        let db = DB::open(opt.clone()).unwrap();
+        let size = 3 * opt.memtable_size as usize;
+        let large = |i: u8| vec![i; size];
+
+        db.set("a", "1").unwrap();
+        // a record larger than a memtable takes a new memtable of its own
+        db.set("b", large(1)).unwrap();
+        let mem_table = db.mem_tables.read().unwrap().mem_table.clone();
+        assert!(mem_table.size() > opt.memtable_size as usize);
+        db.set("c", "1").unwrap();
+        assert!(!Arc::ptr_eq(
+            &mem_table,
+            &db.mem_tables.read().unwrap().mem_table
+        ));
+
+        let mut batch = WriteBatch::new();
+        batch.put("d", large(2));
+        batch.put("e", large(3));
+        db.write(batch).unwrap();
+        assert_eq!(db.get("b").unwrap(), Some(large(1)));
+        assert_eq!(db.get("e").unwrap(), Some(large(3)));
+
+        // the large records come back from the tables and the wal
+        db.flusher.wait(0).unwrap();
+        db.set("f", large(4)).unwrap();
+        drop(db);
+        let db = DB::open(opt).unwrap();
+        assert_eq!(db.get("a").unwrap(), Some("1".as_bytes().to_vec()));
+        assert_eq!(db.get("b").unwrap(), Some(large(1)));
+        assert_eq!(db.get("d").unwrap(), Some(large(2)));
+        assert_eq!(db.get("f").unwrap(), Some(large(4)));
+        assert_eq!(db.iter().count(), 6);
+    }
+
+    #[test]
+    fn test_db_errors() {
+        let mut opt = test_helper::test_options("db_errors");
+        opt.max_level_num = 1;
+        assert!(matches!(
+            DB::open(Arc::new(opt)).err(),
+            Some(Error::InvalidOptions(_))
+        ));
+        // a wal that is disabled can not be synced
+        let mut opt = test_helper::test_options("db_errors");
+        opt.disable_wal = true;
+        opt.wal_sync_mode = WalSyncMode::EveryWrite;
+        assert!(matches!(
+            DB::open(Arc::new(opt)).err(),
+            Some(Error::InvalidOptions(_))
+        ));
+        let mut opt = test_helper::test_options("db_errors");
+        opt.prefix_extractor = Some(PrefixExtractor::Delimited(b'/', 0));
+        assert!(matches!(
+            DB::open(Arc::new(opt)).err(),
+            Some(Error::InvalidOptions(_))
+        ));
+
+        let opt = Arc::new(test_helper::test_options("db_errors"));
+        let db = DB::open(opt.clone()).unwrap();
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        db.flusher.wait(0).unwrap();
+        drop(db);
+
+        // flip a byte and check the error of opening the db, then restore it
+        let corrupt = |name: &str, pos: usize| {
+            let path = std::path::Path::new(&opt.work_dir).join(name);
+            let mut data = std::fs::read(&path).unwrap();
+            data[pos] ^= 0xff;
+            std::fs::write(&path, &data).unwrap();
+            let err = DB::open(opt.clone()).err();
+            data[pos] ^= 0xff;
+            std::fs::write(&path, &data).unwrap();
+            err
+        };
+
+        let manifest_len = std::fs::metadata(std::path::Path::new(&opt.work_dir).join("MANIFEST"))
+            .unwrap()
+            .len() as usize;
+        match corrupt("MANIFEST", manifest_len - 1) {
+            Some(Error::Corruption { file, offset, .. }) => {
+                assert_eq!(file, "MANIFEST");
+                assert!(offset > 0);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let sst_name = file_helper::file_sstable_name(
+            file_helper::load_id_set(&opt.work_dir)
+                .unwrap()
+                .into_iter()
+                .min()
+                .unwrap(),
+        );
+        let sst_len = std::fs::metadata(std::path::Path::new(&opt.work_dir).join(&sst_name))
+            .unwrap()
+            .len() as usize;
+        // a byte of the index block
+        match corrupt(&sst_name, sst_len - 20) {
+            Some(Error::Corruption { file, .. }) => assert_eq!(file, sst_name),
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let db = DB::open(opt.clone()).unwrap();
+        assert_eq!(db.get(&v[0]).unwrap(), Some(v[0].as_bytes().to_vec()));
+        drop(db);
+
+        std::fs::remove_file(std::path::Path::new(&opt.work_dir).join(&sst_name)).unwrap();
+        assert!(matches!(DB::open(opt).err(), Some(Error::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_db_broken_block() {
+        let mut opt = test_helper::test_options("db_broken_block");
+        // tables of many blocks, so a table with a broken block opens
+        opt.block_size = 128;
+        let opt = Arc::new(opt);
+        let db = DB::open(opt.clone()).unwrap();
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        db.flush(true).unwrap();
+        drop(db);
+
+        // flip a byte of the first block of the oldest table
+        let sst_name = file_helper::file_sstable_name(
+            file_helper::load_id_set(&opt.work_dir)
+                .unwrap()
+                .into_iter()
+                .min()
+                .unwrap(),
+        );
+        let path = std::path::Path::new(&opt.work_dir).join(&sst_name);
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        // keys of the broken block are errors, not missing keys
+        let db = DB::open(opt.clone()).unwrap();
+        let mut errors = 0;
+        for x in &v {
+            match db.get(x) {
+                Ok(val) => assert_eq!(val, Some(x.as_bytes().to_vec())),
+                Err(Error::Corruption { file, .. }) => {
+                    assert_eq!(file, sst_name);
+                    errors += 1;
+                }
+                Err(e) => panic!("unexpected {:?}", e),
+            }
+        }
+        assert!(errors > 0);
+
+        let mut iter = db.iter();
+        assert!(iter.by_ref().count() < v.len());
+        assert!(matches!(iter.status(), Err(Error::Corruption { .. })));
+
+        // the compaction fails and keeps its inputs
+        assert!(db.compact_range::<&str>(None, None).await.is_err());
+        assert!(path.exists());
+        drop(db);
+
+        data[0] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        let db = DB::open(opt).unwrap();
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+        assert!(db
+            .iter()
+            .map(|(k, _)| k)
+            .eq(v.iter().map(|x| x.as_bytes().to_vec())));
+    }
+
+    #[test]
+    fn test_db_iter() {
+        let opt = test_helper::test_options("db_iter");
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let v = test_helper::generate_incredible_strings(500);
+        let mut model = std::collections::BTreeMap::new();
+        for x in &v {
+            db.set(x, x).unwrap();
+            model.insert(x.clone(), x.clone());
+        }
+        let snapshot = db.snapshot();
+        let old_model = model.clone();
+        for x in v.iter().step_by(3) {
+            db.set(x.as_str(), "new").unwrap();
+            model.insert(x.clone(), "new".to_string());
+        }
+        for x in v.iter().step_by(7) {
+            db.delete(x).unwrap();
+            model.remove(x);
+        }
+
+        let to_vec = |m: &std::collections::BTreeMap<String, String>| -> Vec<(Slice, Slice)> {
+            m.iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect()
+        };
+        let expect = to_vec(&model);
+        assert_eq!(db.iter().collect::<Vec<_>>(), expect);
+        let mut rev = expect.clone();
+        rev.reverse();
+        assert_eq!(db.iter().rev().collect::<Vec<_>>(), rev);
+
+        // the snapshot sees the old view in both directions
+        let old = to_vec(&old_model);
+        assert_eq!(db.iter_at(&snapshot).collect::<Vec<_>>(), old);
+        let mut old_rev = old.clone();
+        old_rev.reverse();
+        assert_eq!(db.iter_at(&snapshot).rev().collect::<Vec<_>>(), old_rev);
+
+        // ranges
+        let (a, b) = ("abc".to_string(), "acz".to_string());
+        let range = to_vec(
+            &model
+                .range(a.clone()..b.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        assert_eq!(db.range(a.as_str()..b.as_str()).collect::<Vec<_>>(), range);
+        assert_eq!(
+            db.range(a.as_str()..b.as_str()).rev().collect::<Vec<_>>(),
+            range.iter().rev().cloned().collect::<Vec<_>>()
+        );
+        let range = to_vec(
+            &model
+                .range(a.clone()..=b.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        assert_eq!(db.range(a.as_str()..=b.as_str()).collect::<Vec<_>>(), range);
+        let range = to_vec(
+            &model
+                .range(..b.clone())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        assert_eq!(db.range(..b.as_str()).collect::<Vec<_>>(), range);
+        let range = to_vec(
+            &model
+                .range(a.clone()..)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        assert_eq!(db.range(a.as_str()..).rev().count(), range.len());
+
+        // prefix
+        let prefix = to_vec(
+            &model
+                .iter()
+                .filter(|(k, _)| k.starts_with("ab"))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        );
+        assert!(prefix.len() > 1);
+        assert_eq!(db.prefix("ab").collect::<Vec<_>>(), prefix);
+        assert_eq!(
+            db.prefix("ab").rev().collect::<Vec<_>>(),
+            prefix.iter().rev().cloned().collect::<Vec<_>>()
+        );
+
+        // the two ends meet in the middle without crossing
+        let mut iter = db.iter();
+        let mut met = Vec::new();
+        loop {
+            match (iter.next(), iter.next_back()) {
+                (Some(f), Some(b)) => {
+                    met.push(f);
+                    met.push(b);
+                }
+                (Some(f), None) => met.push(f),
+                (None, Some(b)) => met.push(b),
+                (None, None) => break,
+            }
+        }
+        met.sort();
+        assert_eq!(met, expect);
+
+        // seek moves either end
+        let mut iter = db.iter();
+        iter.seek("abja");
+        let expect_key = model.range("abja".to_string()..).next().unwrap().0;
+        assert_eq!(iter.next().unwrap().0, expect_key.as_bytes().to_vec());
+        let mut iter = db.iter();
+        iter.seek_for_prev("abja");
+        let expect_key = model.range(..="abja".to_string()).next_back().unwrap().0;
+        assert_eq!(iter.next_back().unwrap().0, expect_key.as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_db_concurrent() {
+        let opt = test_helper::test_options("db_concurrent");
+        let db = Arc::new(DB::open(Arc::new(opt)).unwrap());
+
+        let (num_writers, num) = (4, 300);
+        let key = |t: usize, i: usize| format!("{:02}-{:04}", t, i);
+        let mut handles = Vec::new();
+        for t in 0..num_writers {
+            let db = db.clone();
+            handles.push(std::thread::spawn(move || {
+                for i in 0..num {
+                    db.set(key(t, i), key(t, i)).unwrap();
+                    // a write is visible to the writer at once
+                    assert_eq!(db.get(key(t, i)).unwrap(), Some(key(t, i).into_bytes()));
+                }
+                for i in (0..num).step_by(2) {
+                    db.delete(key(t, i)).unwrap();
+                }
+            }));
+        }
+        // readers never see a val that was not written
+        for _ in 0..2 {
+            let db = db.clone();
+            handles.push(std::thread::spawn(move || {
+                for round in 0..20 {
+                    for t in 0..num_writers {
+                        let i = (round * 17) % num;
+                        if let Some(val) = db.get(key(t, i)).unwrap() {
+                            assert_eq!(val, key(t, i).into_bytes());
+                        }
+                    }
+                    let mut last: Option<Slice> = None;
+                    for (k, val) in db.iter() {
+                        assert_eq!(k, val);
+                        assert!(last.is_none_or(|last| last < k));
+                        last = Some(k);
+                    }
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        for t in 0..num_writers {
+            for i in 0..num {
+                let expect = if i % 2 == 0 {
+                    None
+                } else {
+                    Some(key(t, i).into_bytes())
+                };
+                assert_eq!(db.get(key(t, i)).unwrap(), expect);
+            }
+        }
+        assert_eq!(db.iter().count(), num_writers * num / 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_db_concurrent_batch() {
+        let opt = test_helper::test_options("db_concurrent_batch");
+        let db = Arc::new(DB::open(Arc::new(opt)).unwrap());
+
+        // every batch sets both keys of a writer to the same val
+        let mut writers = Vec::new();
+        for t in 0..4 {
+            let db = db.clone();
+            writers.push(tokio::spawn(async move {
+                for i in 0..200 {
+                    let mut batch = WriteBatch::new();
+                    batch.put(format!("{}-x", t), i.to_string());
+                    batch.put(format!("{}-y", t), i.to_string());
+                    db.write(batch).unwrap();
+                    tokio::task::yield_now().await;
+                }
+            }));
+        }
+        let reader = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for _ in 0..200 {
+                    let snapshot = db.snapshot();
+                    for t in 0..4 {
+                        let x = db.get_at(&snapshot, format!("{}-x", t)).unwrap();
+                        let y = db.get_at(&snapshot, format!("{}-y", t)).unwrap();
+                        assert_eq!(x, y);
+                    }
+                    let vals: Vec<Slice> = db.iter_at(&snapshot).map(|(_, val)| val).collect();
+                    for pair in vals.chunks(2) {
+                        assert_eq!(pair[0], pair[1]);
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        reader.await.unwrap();
+
+        for t in 0..4 {
+            assert_eq!(
+                db.get(format!("{}-x", t)).unwrap(),
+                Some("199".as_bytes().to_vec())
+            );
+            assert_eq!(
+                db.get(format!("{}-y", t)).unwrap(),
+                Some("199".as_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_db_write_stall() {
+        let mut opt = test_helper::test_options("db_write_stall");
+        opt.max_immu_mem_tables = 2;
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let v = test_helper::generate_incredible_strings(500);
+        for x in &v {
+            db.set(x, x).unwrap();
+            // writes wait for the flusher instead of piling up memtables
+            assert!(db.mem_tables.read().unwrap().immu_mem_tables.len() <= 2);
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+
+        db.flusher.wait(0).unwrap();
+        assert!(db.levels.get_level_num_tables(0) > 0);
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_db_group_commit() {
+        let opt = Arc::new(test_helper::test_options("db_group_commit"));
+        let db = Arc::new(DB::open(opt.clone()).unwrap());
+
+        // writers queue up while the write lock is held, the first of them
+        // writes all of them as a single record
+        let guard = db.write_lock.lock().unwrap();
+        let num = 5;
+        let mut handles = Vec::new();
+        for i in 0..num {
+            let db = db.clone();
+            handles.push(std::thread::spawn(move || {
+                let mut batch = WriteBatch::new();
+                batch.put(format!("key{}", i), format!("val{}", i));
+                db.write_opt(
+                    batch,
+                    &WriteOptions {
+                        sync: true,
+                        ..Default::default()
+                    },
+                )
+            }));
+        }
+        while db.write_queue.lock().unwrap().writes.len() < num {
+            std::thread::yield_now();
+        }
+        drop(guard);
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        assert_eq!(db.write_lock.lock().unwrap().unsynced, 0);
+        for i in 0..num {
+            assert_eq!(
+                db.get(format!("key{}", i)).unwrap(),
+                Some(format!("val{}", i).into_bytes())
+            );
+        }
+
+        let fid = db.mem_tables.read().unwrap().mem_table.id();
+        let wal = WalFile::open(crate::file::file::Options {
+            size: opt.memtable_size,
+            file_name: file_helper::file_wal_name(fid),
+            dir: opt.work_dir.clone(),
+            create: false,
+        })
+        .unwrap();
+        let records = wal.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(WriteBatch::from_data(&records[0]).unwrap().len(), num);
+    }
+
+    #[test]
+    fn test_db_sync_mode() {
+        let mut opt = test_helper::test_options("db_sync_mode");
+        opt.wal_sync_mode = WalSyncMode::Bytes(200);
+        let db = DB::open(Arc::new(opt)).unwrap();
+
+        let mut unsynced = 0;
+        for i in 0..20 {
+            let mut batch = WriteBatch::new();
+            batch.put(format!("key{:02}", i), "val");
+            let record_size = WalFile::record_size(batch.data());
+            db.write(batch).unwrap();
+            // the wal is synced once the unsynced bytes reach the limit
+            unsynced += record_size;
+            if unsynced >= 200 {
+                unsynced = 0;
+            }
+            assert_eq!(db.write_lock.lock().unwrap().unsynced, unsynced);
+        }
+
+        // a sync write syncs whatever the mode is
+        let mut batch = WriteBatch::new();
+        batch.put("key", "val");
+        db.write_opt(
+            batch,
+            &WriteOptions {
+                sync: true,
+                ..Default::default()
             },
-        )?;
+        )
+        .unwrap();
+        assert_eq!(db.write_lock.lock().unwrap().unsynced, 0);
+    }
 
-        self.levels.levels[0].write().unwrap().add(table);
+    #[test]
+    fn test_db_sync_interval() {
+        let mut opt = test_helper::test_options("db_sync_interval");
+        opt.wal_sync_mode = WalSyncMode::Interval(Duration::from_millis(50));
+        let db = DB::open(Arc::new(opt)).unwrap();
 
-        Ok(())
+        db.set("key", "val").unwrap();
+        assert!(db.write_lock.lock().unwrap().unsynced > 0);
+        // no write comes after it, the background thread syncs the wal
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(db.write_lock.lock().unwrap().unsynced, 0);
     }
-}
 
-mod tests {
+    #[test]
+    fn test_db_flush() {
+        let mut opt = test_helper::test_options("db_flush");
+        opt.memtable_size = 1 << 20;
+        let opt = Arc::new(opt);
+        let db = DB::open(opt.clone()).unwrap();
+
+        // nothing to flush
+        db.flush(true).unwrap();
+        assert_eq!(db.levels.get_level_num_tables(0), 0);
+
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v[..100] {
+            db.set(x, x).unwrap();
+        }
+        let fid = db.mem_tables.read().unwrap().mem_table.id();
+        let wal_name = file_helper::file_wal_name_with_dir(&opt.work_dir, fid);
+        assert!(std::path::Path::new(&wal_name).exists());
+        db.flush(true).unwrap();
+        assert_eq!(db.levels.get_level_num_tables(0), 1);
+        assert!(db.mem_tables.read().unwrap().immu_mem_tables.is_empty());
+        assert!(!std::path::Path::new(&wal_name).exists());
+        assert!(db
+            .levels
+            .manifest_file
+            .read()
+            .unwrap()
+            .get_manifest()
+            .tables
+            .contains_key(&fid));
+
+        // flush without wait returns after the memtable is rotated
+        for x in &v[100..200] {
+            db.set(x, x).unwrap();
+        }
+        db.flush(false).unwrap();
+        assert_eq!(db.mem_tables.read().unwrap().mem_table.size(), 0);
+        for x in &v[..200] {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+        db.flusher.wait(0).unwrap();
+        assert_eq!(db.levels.get_level_num_tables(0), 2);
+
+        for x in &v[200..] {
+            db.set(x, x).unwrap();
+        }
+        drop(db);
+
+        // the flushed tables and the wal left are loaded when db opens, the
+        // memtable replayed from the wal is flushed too
+        let db = DB::open(opt).unwrap();
+        db.flush(true).unwrap();
+        assert_eq!(db.levels.get_level_num_tables(0), 3);
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_db_reopen_empty() {
+        let opt = Arc::new(test_helper::test_options("db_reopen_empty"));
+        let db = DB::open(opt.clone()).unwrap();
+        let fid = db.mem_tables.read().unwrap().mem_table.id();
+        drop(db);
+
+        // the empty wal left is dropped with no table built for it
+        let db = DB::open(opt.clone()).unwrap();
+        db.flusher.wait(0).unwrap();
+        assert_eq!(db.levels.get_level_num_tables(0), 0);
+        let wal_name = file_helper::file_wal_name_with_dir(&opt.work_dir, fid);
+        assert!(!std::path::Path::new(&wal_name).exists());
+
+        let v = test_helper::generate_incredible_strings(100);
+        for x in &v {
+            db.set(x, x).unwrap();
+        }
+        db.flush(true).unwrap();
+        for x in &v {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_db_disable_wal() {
+        // writes stay in the active memtable until they are flushed
+        let mut opt = test_helper::test_options("db_disable_wal");
+        opt.memtable_size = 1 << 20;
+        opt.disable_wal = true;
+        let opt = Arc::new(opt);
+        let wal_files = |dir: &str| {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    file_helper::fid_wal(name.to_str().unwrap()).is_ok()
+                })
+                .count()
+        };
+
+        let db = DB::open(opt.clone()).unwrap();
+        let v = test_helper::generate_incredible_strings(300);
+        for x in &v[..200] {
+            db.set(x, x).unwrap();
+        }
+        db.flush(true).unwrap();
+        assert_eq!(db.levels.get_level_num_tables(0), 1);
+        for x in &v[200..] {
+            db.set(x, x).unwrap();
+        }
+        assert_eq!(wal_files(&opt.work_dir), 0);
+        let mut batch = WriteBatch::new();
+        batch.put("key", "val");
+        let res = db.write_opt(
+            batch,
+            &WriteOptions {
+                sync: true,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(res, Err(Error::InvalidArgument(_))));
+        drop(db);
+
+        // the writes after the flush are lost
+        let db = DB::open(opt.clone()).unwrap();
+        for x in &v[..200] {
+            assert_eq!(db.get(x).unwrap(), Some(x.as_bytes().to_vec()));
+        }
+        assert_eq!(db.iter().count(), 200);
+        drop(db);
+
+        // a write may skip the wal while the others do not
+        let mut opt = test_helper::test_options("db_disable_wal_write");
+        opt.memtable_size = 1 << 20;
+        let opt = Arc::new(opt);
+        let db = DB::open(opt.clone()).unwrap();
+        let skip_wal = WriteOptions {
+            disable_wal: true,
+            ..Default::default()
+        };
+        for (i, x) in v.iter().enumerate() {
+            let mut batch = WriteBatch::new();
+            batch.put(x, x);
+            if i % 2 == 0 {
+                db.write_opt(batch, &skip_wal).unwrap();
+            } else {
+                db.write(batch).unwrap();
+            }
+        }
+        drop(db);
+
+        let db = DB::open(opt).unwrap();
+        for (i, x) in v.iter().enumerate() {
+            let expect = if i % 2 == 0 {
+                None
+            } else {
+                Some(x.as_bytes().to_vec())
+            };
+            assert_eq!(db.get(x).unwrap(), expect);
+        }
+    }
 
-    use super::*;
-    use crate::utils::test_helper;
     #[tokio::test]
-    async fn test_db_start() {
-        let opt = Options::test_new();
-        test_helper::work_dir_clear(&opt.work_dir).unwrap();
-        let mut db = DB::open(Arc::new(opt)).unwrap();
+    async fn test_db_close_compactor_error() {
+        let opt = Arc::new(test_helper::test_options("db_close_compactor_error"));
+        let db = DB::open(opt.clone()).unwrap();
+        // a compactor that failed, and one after it that is still running
+        let failed = tokio::spawn(std::future::pending::<()>());
+        failed.abort();
+        let (tx, mut rx) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = tx.send(());
+        });
+        db.compactors.lock().unwrap().extend([failed, running]);
+
+        for i in 0..100 {
+            db.set(format!("key{}", i), "val").unwrap();
+        }
+        db.flush(false).unwrap();
+        assert!(matches!(db.close().await, Err(Error::Background(_))));
+        // the running compactor is waited for, and the memtables are flushed
+        rx.try_recv().unwrap();
+        assert!(db.levels.get_level_num_tables(0) > 0);
+        db.close().await.unwrap();
+        drop(db);
+
+        let db = DB::open(opt).unwrap();
+        assert_eq!(db.get("key99").unwrap(), Some("val".as_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_db_close() {
+        let opt = Arc::new(test_helper::test_options("db_close"));
+        let db = DB::open(opt.clone()).unwrap();
         db.start_compacter().await;
 
-        let v = test_helper::generate_incredible_strings(1000);
+        let v = test_helper::generate_incredible_strings(500);
         for x in &v {
             db.set(x, x).unwrap();
         }
+        for x in v.iter().step_by(2) {
+            db.delete(x).unwrap();
+        }
+        // close waits for the flush without blocking the runtime, so it can
+        // run as a task of it
+        let db = Arc::new(db);
+        let closer = db.clone();
+        tokio::spawn(async move { closer.close().await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(db.compactors.lock().unwrap().is_empty());
+        assert!(matches!(db.set("key", "val"), Err(Error::Closed)));
+        // closing twice is fine
+        db.close().await.unwrap();
+        drop(db);
+
+        // reopen the dir, tables come from the manifest and the rest from the wal
+        let db = DB::open(opt.clone()).unwrap();
+        assert!(db.levels.get_level_num_tables(0) > 0);
+        for (i, x) in v.iter().enumerate() {
+            let expect = if i % 2 == 0 {
+                None
+            } else {
+                Some(x.as_bytes().to_vec())
+            };
+            assert_eq!(db.get(x).unwrap(), expect);
+        }
+        db.set("key", "val").unwrap();
+        drop(db);
 
-        loop {}
+        // a db that is dropped without closing can be reopened too
+        let db = DB::open(opt).unwrap();
+        assert_eq!(db.get("key").unwrap(), Some("val".as_bytes().to_vec()));
+        assert_eq!(db.get(&v[1]).unwrap(), Some(v[1].as_bytes().to_vec()));
+        assert_eq!(db.iter().count(), v.len() / 2 + 1);
     }
 }
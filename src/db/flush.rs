@@ -0,0 +1,186 @@
+use super::level::LevelManager;
+use super::memtable::MemTable;
+use super::options::Options;
+use crate::error::{Error, Result};
+use crate::file::manifest::TableMeta;
+use crate::table::table::Table;
+use crate::table::table_builder::TableBuilder;
+use crate::utils::file::file_helper;
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::thread::JoinHandle;
+
+// the active memtable and immu memtables waiting to be flushed, immu memtables
+// are in the order they were rotated, the oldest is the first
+pub(crate) struct MemTables {
+    pub(crate) mem_table: Arc<MemTable>,
+    pub(crate) immu_mem_tables: Vec<Arc<MemTable>>,
+}
+
+// Flusher flushes immu memtables to level 0 in a background thread, writers
+// hand memtables over by pushing them to the immu queue and scheduling a flush
+pub(crate) struct Flusher {
+    state: Arc<FlushState>,
+    tx: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct FlushState {
+    mem_tables: Arc<RwLock<MemTables>>,
+    levels: Arc<LevelManager>,
+    opt: Arc<Options>,
+    // the error that stopped flushing, waiters are woken up by cond when a
+    // memtable is flushed or flushing fails
+    error: Mutex<Option<Error>>,
+    cond: Condvar,
+}
+
+impl Flusher {
+    pub fn start(
+        mem_tables: Arc<RwLock<MemTables>>,
+        levels: Arc<LevelManager>,
+        opt: Arc<Options>,
+    ) -> Flusher {
+        let state = Arc::new(FlushState {
+            mem_tables,
+            levels,
+            opt,
+            error: Mutex::new(None),
+            cond: Condvar::new(),
+        });
+        let (tx, rx) = mpsc::channel();
+        let handle = {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                // every message asks to flush all immu memtables, the thread stops
+                // when the flusher is dropped
+                while rx.recv().is_ok() {
+                    state.flush_all();
+                }
+            })
+        };
+        Flusher {
+            state,
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn schedule(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(());
+        }
+    }
+
+    // wait until at most n immu memtables are left
+    pub fn wait(&self, n: usize) -> Result<()> {
+        self.state.wait(n)
+    }
+
+    // wait_async is wait for async callers, the wait blocks a thread of the
+    // blocking pool instead of a worker of the runtime
+    pub async fn wait_async(&self, n: usize) -> Result<()> {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || state.wait(n))
+            .await
+            .map_err(|e| Error::Background(e.to_string()))?
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl FlushState {
+    fn wait(&self, n: usize) -> Result<()> {
+        let mut error = self.error.lock().unwrap();
+        loop {
+            if let Some(e) = &*error {
+                return Err(e.clone());
+            }
+            if self.mem_tables.read().unwrap().immu_mem_tables.len() <= n {
+                return Ok(());
+            }
+            error = self.cond.wait(error).unwrap();
+        }
+    }
+
+    // flush immu memtables from the oldest, a memtable is removed from the
+    // queue only after its table is in level 0, so readers always find it
+    fn flush_all(&self) {
+        loop {
+            let oldest = self
+                .mem_tables
+                .read()
+                .unwrap()
+                .immu_mem_tables
+                .first()
+                .cloned();
+            let Some(immu_mem_table) = oldest else {
+                return;
+            };
+            let res = self.flush_memtable(&immu_mem_table);
+
+            // the wal is useless once the table is in level 0, it is removed
+            // before waiters are woken up. there is no wal file if the wal is disabled
+            if res.is_ok() {
+                let wal_name =
+                    file_helper::file_wal_name_with_dir(&self.opt.work_dir, immu_mem_table.id());
+                let _ = std::fs::remove_file(wal_name);
+            }
+
+            let mut error = self.error.lock().unwrap();
+            match res {
+                Ok(()) => {
+                    self.mem_tables.write().unwrap().immu_mem_tables.remove(0);
+                    self.cond.notify_all();
+                }
+                Err(e) => {
+                    *error = Some(e);
+                    self.cond.notify_all();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn flush_memtable(&self, immu_mem_table: &MemTable) -> Result<()> {
+        // a memtable without entries, e.g. replayed from an empty wal, gets
+        // no table, its wal is removed as if it were flushed
+        if immu_mem_table.skiplist.is_empty() {
+            return Ok(());
+        }
+
+        // alloc a fid
+        let fid = immu_mem_table.id();
+        let sst_name = file_helper::file_sstable_name(fid);
+
+        let mut table_builder = TableBuilder::with_level(self.opt.clone(), 0);
+        for entry in immu_mem_table.skiplist.iter() {
+            let (key, val) = (entry.key(), entry.value());
+
+            table_builder.add(&key.0, val);
+        }
+
+        // create a table
+
+        let table = Table::open(self.opt.clone(), sst_name, Some(table_builder), 0)?;
+        let mut manifest_file = self.levels.manifest_file.write().unwrap();
+
+        manifest_file.add_table_meta(
+            0,
+            TableMeta {
+                id: fid,
+                checksum: "bupt".as_bytes().to_vec(),
+            },
+        )?;
+
+        self.levels.levels[0].write().unwrap().add(table);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use crate::utils::slice::Slice;
+use std::cmp::Ordering;
+
+// ValueType marks what an entry stands for, a deleted key keeps a tombstone
+// that shadows older vals in lower levels until compaction drops it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    Deletion = 0,
+    Value = 1,
+}
+
+// sequence number takes the high 56 bits of the tag, kind takes the low 8 bits
+pub const MAX_SEQUENCE: u64 = (1 << 56) - 1;
+
+// size of the tag appended to the user key
+pub const TAG_SIZE: usize = 8;
+
+impl ValueType {
+    pub fn from_u8(b: u8) -> Option<ValueType> {
+        match b {
+            0 => Some(ValueType::Deletion),
+            1 => Some(ValueType::Value),
+            _ => None,
+        }
+    }
+}
+
+// internal key is the key stored in memtables, wal files and sst files,
+// its pattern is |user key| |seq << 8 | kind|, the tag is a fixed u64 in little endian
+pub fn make_internal_key(user_key: &[u8], seq: u64, kind: ValueType) -> Slice {
+    assert!(seq <= MAX_SEQUENCE);
+    let mut v = Vec::with_capacity(user_key.len() + TAG_SIZE);
+    v.extend_from_slice(user_key);
+    v.extend_from_slice(&(seq << 8 | kind as u64).to_le_bytes());
+    v
+}
+
+// lookup key seeks to the newest entry of the user key that is visible at seq,
+// Value is the largest kind, so it sorts before any entry with the same seq
+pub fn make_lookup_key(user_key: &[u8], seq: u64) -> Slice {
+    make_internal_key(user_key, seq, ValueType::Value)
+}
+
+// parse an internal key to user key, seq and kind, return None if it is corrupted
+pub fn parse_internal_key(ikey: &[u8]) -> Option<(&[u8], u64, ValueType)> {
+    if ikey.len() < TAG_SIZE {
+        return None;
+    }
+    let (user_key, tag) = ikey.split_at(ikey.len() - TAG_SIZE);
+    let tag = u64::from_le_bytes(tag.try_into().unwrap());
+    let kind = ValueType::from_u8((tag & 0xff) as u8)?;
+    Some((user_key, tag >> 8, kind))
+}
+
+pub fn user_key(ikey: &[u8]) -> &[u8] {
+    &ikey[..ikey.len() - TAG_SIZE]
+}
+
+pub fn seq(ikey: &[u8]) -> u64 {
+    let tag = &ikey[ikey.len() - TAG_SIZE..];
+    u64::from_le_bytes(tag.try_into().unwrap()) >> 8
+}
+
+// internal keys are ordered by user key in ascending order, then by tag in
+// descending order, so the newest version of a user key comes first
+pub fn compare_internal_key(a: &[u8], b: &[u8]) -> Ordering {
+    user_key(a).cmp(user_key(b)).then_with(|| {
+        let a_tag = u64::from_le_bytes(a[a.len() - TAG_SIZE..].try_into().unwrap());
+        let b_tag = u64::from_le_bytes(b[b.len() - TAG_SIZE..].try_into().unwrap());
+        b_tag.cmp(&a_tag)
+    })
+}
+
+// InternalKey wraps an internal key to order it by compare_internal_key in skiplist
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InternalKey(pub Slice);
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_internal_key(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_key_encoding() {
+        let ikey = make_internal_key(b"hello", 7, ValueType::Value);
+        assert_eq!(
+            parse_internal_key(&ikey),
+            Some((&b"hello"[..], 7, ValueType::Value))
+        );
+        assert_eq!(user_key(&ikey), b"hello");
+        assert_eq!(seq(&ikey), 7);
+
+        // an empty user key is a legal key
+        let ikey = make_internal_key(b"", MAX_SEQUENCE, ValueType::Deletion);
+        assert_eq!(
+            parse_internal_key(&ikey),
+            Some((&b""[..], MAX_SEQUENCE, ValueType::Deletion))
+        );
+
+        assert_eq!(parse_internal_key(b"short"), None);
+        let mut ikey = make_internal_key(b"k", 1, ValueType::Value);
+        *ikey.last_mut().unwrap() = 0;
+        ikey[1] = 7;
+        assert_eq!(parse_internal_key(&ikey), None);
+    }
+
+    #[test]
+    fn test_internal_key_order() {
+        let a1 = make_internal_key(b"a", 1, ValueType::Value);
+        let a2 = make_internal_key(b"a", 2, ValueType::Deletion);
+        let ab = make_internal_key(b"ab", 3, ValueType::Value);
+        let b = make_internal_key(b"b", 0, ValueType::Value);
+
+        // newer version of the same user key comes first
+        assert_eq!(compare_internal_key(&a2, &a1), Ordering::Less);
+        // user key decides before seq, even if one user key is a prefix of the other
+        assert_eq!(compare_internal_key(&a1, &ab), Ordering::Less);
+        assert_eq!(compare_internal_key(&ab, &b), Ordering::Less);
+
+        // lookup key does not sort after any entry visible at its seq
+        let lookup = make_lookup_key(b"a", 2);
+        assert_eq!(compare_internal_key(&lookup, &a2), Ordering::Less);
+        assert_eq!(compare_internal_key(&lookup, &a1), Ordering::Less);
+        let lookup = make_lookup_key(b"a", 1);
+        assert_eq!(compare_internal_key(&a2, &lookup), Ordering::Less);
+        assert_eq!(compare_internal_key(&lookup, &a1), Ordering::Equal);
+    }
+}
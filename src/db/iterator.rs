@@ -1,99 +1,362 @@
-use crate::table::table::TableIterator;
+use crate::db::format::{self, ValueType};
+use crate::error::Result;
 use crate::utils::slice::Slice;
 use std::cmp::Ordering;
 use std::collections::binary_heap::BinaryHeap;
+use std::ops::Bound;
 
 pub trait DBIterator {
     fn seek_to_first(&mut self);
+    fn seek_to_last(&mut self);
     fn seek(&mut self, key: &Slice) -> Option<&Slice>;
     fn next(&mut self) -> Option<()>;
+    fn prev(&mut self) -> Option<()>;
+    fn valid(&self) -> bool;
     fn key(&self) -> &Slice;
     fn val(&self) -> &Slice;
+    // status is the error that made the iterator invalid, an iterator that
+    // can not fail keeps the default
+    fn status(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
+pub type BoxedIterator = Box<dyn DBIterator + Send>;
+
 #[derive(PartialEq, Eq)]
 struct Item {
     key: Slice,
     val: Slice,
     idx: usize,
+    reverse: bool,
 }
 
 // if Item try to own &key of iters, but move iters in MergeIterator, it will
 // conflict
-pub struct MergeIterator<'a> {
+pub struct MergeIterator {
     heap: BinaryHeap<Item>,
-    iters: Vec<TableIterator<'a>>,
+    iters: Vec<BoxedIterator>,
+    reverse: bool,
 }
 
+// keys are internal keys, so the heap pops the newest version of the
+// smallest user key first, or the oldest version of the largest user key
+// first when it goes in reverse
 impl Ord for Item {
     fn cmp(&self, other: &Self) -> Ordering {
-        other
-            .key
-            .cmp(&self.key)
-            .then_with(|| other.idx.cmp(&self.idx))
+        let ord = format::compare_internal_key(&self.key, &other.key)
+            .then_with(|| self.idx.cmp(&other.idx));
+        if self.reverse {
+            ord
+        } else {
+            ord.reverse()
+        }
     }
 }
 
 impl PartialOrd for Item {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(other.cmp(self))
+        Some(self.cmp(other))
     }
 }
 
-impl<'a> MergeIterator<'a> {
-    pub fn new(mut iters: Vec<TableIterator<'a>>) -> Self {
-        let mut heap = BinaryHeap::new();
+impl MergeIterator {
+    // the iterator yields nothing until it is positioned by a seek
+    pub fn new(iters: Vec<BoxedIterator>) -> Self {
+        MergeIterator {
+            heap: BinaryHeap::new(),
+            iters,
+            reverse: false,
+        }
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.reset(false);
+        for idx in 0..self.iters.len() {
+            self.iters[idx].seek_to_first();
+            self.push(idx);
+        }
+    }
+
+    // seek_to_last makes the iterator go in reverse from the largest key
+    pub fn seek_to_last(&mut self) {
+        self.reset(true);
+        for idx in 0..self.iters.len() {
+            self.iters[idx].seek_to_last();
+            self.push(idx);
+        }
+    }
+
+    // seek every iterator to the first key greater or equal than key
+    pub fn seek(&mut self, key: &Slice) -> Option<()> {
+        self.reset(false);
+        for idx in 0..self.iters.len() {
+            if self.iters[idx].seek(key).is_some() {
+                self.push(idx);
+            }
+        }
+        if self.heap.is_empty() {
+            None
+        } else {
+            Some(())
+        }
+    }
+
+    // seek every iterator to the last key less or equal than key, the
+    // iterator goes in reverse from it
+    pub fn seek_for_prev(&mut self, key: &Slice) -> Option<()> {
+        self.reset(true);
+        for idx in 0..self.iters.len() {
+            let iter = &mut self.iters[idx];
+            match iter.seek(key) {
+                None => iter.seek_to_last(),
+                Some(found) if format::compare_internal_key(found, key).is_gt() => {
+                    iter.prev();
+                }
+                Some(_) => {}
+            }
+            self.push(idx);
+        }
+        if self.heap.is_empty() {
+            None
+        } else {
+            Some(())
+        }
+    }
+
+    // the key that next returns
+    pub fn peek_key(&self) -> Option<&Slice> {
+        self.heap.peek().map(|item| &item.key)
+    }
+
+    // status is the first error of the iterators, the merged keys stop early
+    // if an iterator failed, so they must not be taken as all keys
+    pub fn status(&self) -> Result<()> {
+        self.iters.iter().try_for_each(|iter| iter.status())
+    }
 
-        for (idx, iter) in iters.iter_mut().enumerate() {
-            iter.seek_to_first();
+    fn reset(&mut self, reverse: bool) {
+        self.heap.clear();
+        self.reverse = reverse;
+    }
 
-            let key = iter.key();
-            let val = iter.val();
-            heap.push(Item {
-                key: key.clone(),
-                val: val.clone(),
+    fn push(&mut self, idx: usize) {
+        let iter = &self.iters[idx];
+        if iter.valid() {
+            self.heap.push(Item {
+                key: iter.key().clone(),
+                val: iter.val().clone(),
                 idx,
+                reverse: self.reverse,
             });
         }
+    }
+}
+
+impl Iterator for MergeIterator {
+    type Item = (Slice, Slice);
 
-        MergeIterator { heap, iters }
+    fn next(&mut self) -> Option<Self::Item> {
+        let Item { key, val, idx, .. } = self.heap.pop()?;
+        let moved = if self.reverse {
+            self.iters[idx].prev()
+        } else {
+            self.iters[idx].next()
+        };
+        if moved.is_some() {
+            self.push(idx);
+        }
+        Some((key, val))
     }
+}
 
-    pub fn seek(&mut self, key: &Slice) -> Option<()> {
-        self.heap.clear();
-        for (idx, iter) in self.iters.iter_mut().enumerate() {
-            // try to seek each iterator to the target key
-            if iter.seek(key).is_some() {
-                let key = iter.key();
-                let val = iter.val();
-                self.heap.push(Item {
-                    key: key.clone(),
-                    val: val.clone(),
-                    idx,
-                });
-
-                return Some(());
-            }
+// DBIter iterates user keys and vals of the db in a range as they were at seq,
+// it yields the newest visible version of each user key and hides tombstones.
+// it goes forward with next and in reverse with next_back, the two ends
+// never cross
+pub struct DBIter {
+    front: MergeIterator,
+    back: MergeIterator,
+    seq: u64,
+    range: (Bound<Slice>, Bound<Slice>),
+    // user keys not yielded yet are between lower and upper, they start
+    // from the range and shrink as keys are yielded from either end
+    lower: Bound<Slice>,
+    upper: Bound<Slice>,
+    front_positioned: bool,
+    back_positioned: bool,
+}
+
+impl DBIter {
+    // front and back are iterators of the same memtables and tables, one
+    // for each end
+    pub(crate) fn new(
+        front: Vec<BoxedIterator>,
+        back: Vec<BoxedIterator>,
+        seq: u64,
+        range: (Bound<Slice>, Bound<Slice>),
+    ) -> Self {
+        DBIter {
+            front: MergeIterator::new(front),
+            back: MergeIterator::new(back),
+            seq,
+            lower: range.0.clone(),
+            upper: range.1.clone(),
+            range,
+            front_positioned: false,
+            back_positioned: false,
         }
-        None
+    }
+
+    // seek makes next start from the first key greater or equal than key
+    // in the range
+    pub fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = key.as_ref();
+        self.lower = if above(&self.range.0, key) {
+            Bound::Included(key.to_vec())
+        } else {
+            self.range.0.clone()
+        };
+        self.front_positioned = false;
+    }
+
+    // seek_for_prev makes next_back start from the last key less or equal
+    // than key in the range
+    pub fn seek_for_prev<K: AsRef<[u8]>>(&mut self, key: K) {
+        let key = key.as_ref();
+        self.upper = if below(&self.range.1, key) {
+            Bound::Included(key.to_vec())
+        } else {
+            self.range.1.clone()
+        };
+        self.back_positioned = false;
+    }
+
+    // status is the error that ended the iteration early, e.g. a broken block
+    // of a table, it is Ok if the iteration reached the end of the range
+    pub fn status(&self) -> Result<()> {
+        self.front.status()?;
+        self.back.status()
     }
 }
 
-impl<'a> Iterator for MergeIterator<'a> {
+impl Iterator for DBIter {
     type Item = (Slice, Slice);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(Item { key, val, idx }) = self.heap.pop() {
-            if let Some(()) = self.iters[idx].next() {
-                self.heap.push(Item {
-                    key: self.iters[idx].key().clone(),
-                    val: self.iters[idx].val().clone(),
-                    idx,
-                });
+        if !self.front_positioned {
+            match &self.lower {
+                Bound::Unbounded => self.front.seek_to_first(),
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    self.front
+                        .seek(&format::make_lookup_key(key, format::MAX_SEQUENCE));
+                }
+            }
+            self.front_positioned = true;
+        }
+
+        for (ikey, val) in self.front.by_ref() {
+            let Some((user_key, seq, kind)) = format::parse_internal_key(&ikey) else {
+                continue;
+            };
+            // written after the iterator was created, or an older version
+            // of a user key that has been met
+            if seq > self.seq || !above(&self.lower, user_key) {
+                continue;
+            }
+            if !below(&self.upper, user_key) {
+                return None;
+            }
+            self.lower = Bound::Excluded(user_key.to_vec());
+
+            if kind == ValueType::Value {
+                return Some((user_key.to_vec(), val));
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for DBIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if !self.back_positioned {
+            match &self.upper {
+                Bound::Unbounded => self.back.seek_to_last(),
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    // the smallest tag sorts after all versions of the key
+                    self.back.seek_for_prev(&format::make_internal_key(
+                        key,
+                        0,
+                        ValueType::Deletion,
+                    ));
+                }
+            }
+            self.back_positioned = true;
+        }
+
+        while let Some((ikey, val)) = self.back.next() {
+            let Some((user_key, seq, kind)) = format::parse_internal_key(&ikey) else {
+                continue;
+            };
+            if seq > self.seq || !below(&self.upper, user_key) {
+                continue;
+            }
+            if !above(&self.lower, user_key) {
+                return None;
+            }
+
+            // versions of a user key come from the oldest to the newest in
+            // reverse, so the last visible one is the newest
+            let (mut kind, mut val) = (kind, val);
+            while let Some(next_key) = self.back.peek_key() {
+                if format::user_key(next_key) != user_key {
+                    break;
+                }
+                let (next_key, next_val) = self.back.next().unwrap();
+                match format::parse_internal_key(&next_key) {
+                    Some((_, seq, next_kind)) if seq <= self.seq => {
+                        kind = next_kind;
+                        val = next_val;
+                    }
+                    _ => {}
+                }
+            }
+            self.upper = Bound::Excluded(user_key.to_vec());
+
+            if kind == ValueType::Value {
+                return Some((user_key.to_vec(), val));
             }
-            Some((key, val))
-        } else {
-            None
         }
+        None
+    }
+}
+
+// prefix_range is the range of user keys that start with prefix
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Slice>, Bound<Slice>) {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+// above tells if key is not less than the lower bound
+fn above(lower: &Bound<Slice>, key: &[u8]) -> bool {
+    match lower {
+        Bound::Unbounded => true,
+        Bound::Included(bound) => key >= bound.as_slice(),
+        Bound::Excluded(bound) => key > bound.as_slice(),
+    }
+}
+
+// below tells if key is not greater than the upper bound
+fn below(upper: &Bound<Slice>, key: &[u8]) -> bool {
+    match upper {
+        Bound::Unbounded => true,
+        Bound::Included(bound) => key <= bound.as_slice(),
+        Bound::Excluded(bound) => key < bound.as_slice(),
     }
 }
@@ -1,14 +1,13 @@
 use super::compact::CompactStatus;
+use super::format::{self, ValueType};
 use super::options::Options;
+use super::snapshot::SnapshotList;
+use crate::error::{Error, Result};
 use crate::file::manifest::ManifestFile;
-use crate::table::table::Table;
+use crate::table::table::{BloomStats, Table};
 use crate::utils::file::file_helper;
 use crate::utils::slice::Slice;
-use std::collections::HashSet;
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc, RwLock,
-};
+use std::sync::{atomic::Ordering, Arc, RwLock};
 
 pub type Level = Arc<RwLock<LevelHandler>>;
 pub(crate) struct LevelManager {
@@ -16,28 +15,35 @@ pub(crate) struct LevelManager {
     pub(crate) manifest_file: RwLock<ManifestFile>,
     pub(crate) levels: Vec<Level>,
     pub(crate) compact_state: RwLock<CompactStatus>,
+    // live snapshots, compaction keeps the versions they can see
+    pub(crate) snapshots: Arc<SnapshotList>,
+    // how the bloom filters of each level answer point lookups
+    pub(crate) bloom_stats: Vec<BloomStats>,
 }
 
 #[derive(Default)]
 pub(crate) struct LevelHandler {
     pub(crate) level_num: u32,
-    pub(crate) tables: Vec<Table>,
+    pub(crate) tables: Vec<Arc<Table>>,
     pub(crate) total_size: u64,
 }
 
 impl LevelManager {
-    pub fn new(opt: Arc<Options>) -> Result<LevelManager, String> {
-        let manifest_file = ManifestFile::open(opt.clone())
-            .map_err(|e| format!("failed to open the manifest, {}", e))?;
+    pub fn new(opt: Arc<Options>) -> Result<LevelManager> {
+        let manifest_file = ManifestFile::open(opt.clone())?;
 
-        let id_set = file_helper::load_id_set(&opt.work_dir)
-            .map_err(|e| format!("failed to load id set, {}", e))?;
+        let id_set = file_helper::load_id_set(&opt.work_dir)?;
 
         // verify the correctness of the manifest file
         manifest_file.revert(id_set)?;
 
         let levels: Vec<RwLock<LevelHandler>> = (0..opt.max_level_num)
-            .map(|_| RwLock::new(LevelHandler::default()))
+            .map(|i| {
+                RwLock::new(LevelHandler {
+                    level_num: i,
+                    ..Default::default()
+                })
+            })
             .collect();
 
         let manifest = manifest_file.get_manifest();
@@ -47,38 +53,46 @@ impl LevelManager {
             let file_name = file_helper::file_sstable_name(fid);
             max_fid = std::cmp::max(max_fid, fid);
 
-            let table = Table::open(opt.clone(), file_name, None)
-                .map_err(|e| format!("faild to open the table {}, {}", &fid, e))?;
+            let table = Table::open(opt.clone(), file_name, None, table_info.level as u32)?;
 
-            let mut level = levels[table_info.level as usize]
-                .write()
-                .map_err(|e| format!("failed to lock level for writing, {}", e))?;
+            let level = levels.get(table_info.level as usize).ok_or_else(|| {
+                Error::InvalidOptions(format!(
+                    "table {} is in level {}, but max_level_num is {}",
+                    fid, table_info.level, opt.max_level_num
+                ))
+            })?;
+            let mut level = level.write().unwrap();
 
             level.add(table);
         }
 
         opt.max_fid.store(max_fid, Ordering::Relaxed);
         for level in &levels {
-            let mut level = level.write().map_err(|e| e.to_string())?;
+            let mut level = level.write().unwrap();
 
             level.sort();
         }
-        let levels = levels.into_iter().map(|x| Arc::new(x)).collect();
+        let levels = levels.into_iter().map(Arc::new).collect();
+        let bloom_stats = (0..opt.max_level_num)
+            .map(|_| BloomStats::default())
+            .collect();
 
         Ok(LevelManager {
             opt: opt.clone(),
             manifest_file: RwLock::new(manifest_file),
             levels,
             compact_state: RwLock::new(CompactStatus::new(opt)),
+            snapshots: Arc::new(SnapshotList::default()),
+            bloom_stats,
         })
     }
 
     pub fn get_level_num_tables(&self, idx: usize) -> u32 {
-        self.levels[idx as usize].read().unwrap().tables.len() as u32
+        self.levels[idx].read().unwrap().tables.len() as u32
     }
 
     pub fn get_level_total_size(&self, idx: usize) -> u64 {
-        self.levels[idx as usize].read().unwrap().total_size
+        self.levels[idx].read().unwrap().total_size
     }
 
     // to replace level tables
@@ -86,14 +100,12 @@ impl LevelManager {
         &self,
         level: u32,
         mut del_tables: Vec<u32>,
-        mut new_tables: Vec<Table>,
+        new_tables: Vec<Table>,
     ) {
         let mut level = self.levels[level as usize].write().unwrap();
 
-        let mut count = 0;
-        for i in &mut del_tables {
-            *i -= count;
-            count += 1;
+        for (count, i) in del_tables.iter_mut().enumerate() {
+            *i -= count as u32;
         }
 
         for i in del_tables {
@@ -102,7 +114,9 @@ impl LevelManager {
             level.tables.remove(i as usize);
         }
 
-        level.tables.append(&mut new_tables);
+        for table in new_tables {
+            level.add(table);
+        }
         level.sort();
     }
 
@@ -110,10 +124,8 @@ impl LevelManager {
     pub fn delete_level_tables(&self, level: u32, mut del_tables: Vec<u32>) {
         let mut level = self.levels[level as usize].write().unwrap();
 
-        let mut count = 0;
-        for i in &mut del_tables {
-            *i -= count;
-            count += 1;
+        for (count, i) in del_tables.iter_mut().enumerate() {
+            *i -= count as u32;
         }
 
         for i in del_tables {
@@ -123,59 +135,129 @@ impl LevelManager {
         }
     }
 
-    // get val form the key
-    pub fn get(&self, key: &[u8]) -> Option<Slice> {
-        if let Some(val) = self.search_L0_sst(key) {
-            return Some(val);
+    // get the newest entry of the key whose seq is not greater than seq, a
+    // table that can not be read fails the lookup, as older levels may hold
+    // a stale version of the key
+    pub fn get(&self, key: &[u8], seq: u64) -> Result<Option<(ValueType, Slice)>> {
+        let lookup_key = format::make_lookup_key(key, seq);
+        if let Some(entry) = self.search_l0_sst(key, &lookup_key)? {
+            return Ok(Some(entry));
         }
         for i in 1..self.opt.max_level_num {
-            if let Some(val) = self.search_ln_sst(i, key) {
-                return Some(val);
+            if let Some(entry) = self.search_ln_sst(i, key, &lookup_key)? {
+                return Ok(Some(entry));
             }
         }
 
-        None
+        Ok(None)
     }
 
     // search key in L0 ssts
-    pub fn search_L0_sst(&self, key: &[u8]) -> Option<Slice> {
+    pub fn search_l0_sst(
+        &self,
+        key: &[u8],
+        lookup_key: &[u8],
+    ) -> Result<Option<(ValueType, Slice)>> {
         let tables = &self.levels[0].read().unwrap().tables;
-        for table in tables {
-            let mut iter = table.new_iterator();
-            if let Some(val) = iter.seek(key) {
-                return Some(val.clone());
+        // tables in level 0 overlap and are searched from the newest. a table
+        // written by a compaction inside level 0 has the newest id, but may hold
+        // entries older than tables left out of it, so the search goes on to
+        // tables that hold entries newer than the entry found
+        let mut found: Option<(Slice, Slice)> = None;
+        for table in tables.iter().rev() {
+            if let Some((found_key, _)) = &found {
+                if table.max_seq() <= format::seq(found_key) {
+                    continue;
+                }
+            }
+            if key < format::user_key(table.min_key()) || key > format::user_key(table.max_key()) {
+                continue;
+            }
+            if let Some((ikey, val)) = table.get(key, lookup_key, &self.bloom_stats[0])? {
+                let newer = match &found {
+                    Some((found_key, _)) => format::seq(&ikey) > format::seq(found_key),
+                    None => true,
+                };
+                if newer {
+                    found = Some((ikey, val));
+                }
             }
         }
-        None
+        let Some((ikey, val)) = found else {
+            return Ok(None);
+        };
+        Ok(format::parse_internal_key(&ikey).map(|(_, _, kind)| (kind, val)))
     }
 
-    // search key in LN ssts
-    pub fn search_ln_sst(&self, level: u32, key: &[u8]) -> Option<Slice> {
+    // search key in LN ssts, tables in the level are sorted and their user key
+    // ranges do not overlap, so the only table that may hold the key is the
+    // first one whose max key is not less than it, found by binary search.
+    // no hint is passed from the level above, as in fractional cascading, it
+    // would point into tables that a compaction of the level below replaces
+    pub fn search_ln_sst(
+        &self,
+        level: u32,
+        key: &[u8],
+        lookup_key: &[u8],
+    ) -> Result<Option<(ValueType, Slice)>> {
         let tables = &self.levels[level as usize].read().unwrap().tables;
-        if tables.is_empty() {
-            return None;
+        let idx = tables.partition_point(|table| format::user_key(table.max_key()) < key);
+        let Some(table) = tables.get(idx) else {
+            return Ok(None);
+        };
+        if key < format::user_key(table.min_key()) {
+            return Ok(None);
         }
+        let Some((ikey, val)) = table.get(key, lookup_key, &self.bloom_stats[level as usize])?
+        else {
+            return Ok(None);
+        };
+        Ok(format::parse_internal_key(&ikey).map(|(_, _, kind)| (kind, val)))
+    }
 
-        if key.cmp(&tables[0].min_key()).is_lt() {
-            return None;
-        } else {
-            for table in tables {
-                if key.cmp(&table.min_key()).is_ge() && key.cmp(&table.max_key()).is_le() {
-                    let mut iter = table.new_iterator();
-                    if let Some(val) = iter.seek(key) {
-                        return Some(val.clone());
-                    }
-                }
+    // tables in all levels
+    pub fn tables(&self) -> Vec<Arc<Table>> {
+        let mut tables = Vec::new();
+        for level in &self.levels {
+            tables.extend(level.read().unwrap().tables.iter().cloned());
+        }
+        tables
+    }
+
+    // tables in all levels that may hold keys that start with prefix, a level
+    // whose tables are all out of the prefix is skipped. a table whose filter
+    // can not be read is kept, its iterator reports the error in status
+    pub fn prefix_tables(&self, prefix: &[u8]) -> Vec<Arc<Table>> {
+        let mut tables = Vec::new();
+        for level in &self.levels {
+            let level = level.read().unwrap();
+            tables.extend(
+                level
+                    .tables
+                    .iter()
+                    .filter(|table| table.may_contain_prefix(prefix).unwrap_or(true))
+                    .cloned(),
+            );
+        }
+        tables
+    }
+
+    // the largest seq of entries in all levels
+    pub fn max_seq(&self) -> u64 {
+        let mut max_seq = 0;
+        for level in &self.levels {
+            for table in &level.read().unwrap().tables {
+                max_seq = std::cmp::max(max_seq, table.max_seq());
             }
         }
-        None
+        max_seq
     }
 }
 
 impl LevelHandler {
     pub fn add(&mut self, t: Table) {
         self.total_size += t.size();
-        self.tables.push(t);
+        self.tables.push(Arc::new(t));
     }
 
     pub fn sort(&mut self) {
@@ -190,7 +272,64 @@ impl LevelHandler {
             });
         } else {
             self.tables
-                .sort_by(|lhs, rhs| lhs.min_key().cmp(rhs.min_key()));
+                .sort_by(|lhs, rhs| format::compare_internal_key(lhs.min_key(), rhs.min_key()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::table_builder::TableBuilder;
+    use crate::utils::test_helper;
+
+    // add_tables adds num_tables tables to the level, table i holds the even
+    // keys of [i * 100, i * 100 + num_keys * 2), so keys are missing both in
+    // tables and between them
+    fn add_tables(levels: &LevelManager, level: u32, num_tables: u64, num_keys: u64) {
+        let mut handler = levels.levels[level as usize].write().unwrap();
+        for i in 0..num_tables {
+            let mut builder = TableBuilder::new(levels.opt.clone());
+            for k in 0..num_keys {
+                let key = format!("key{:08}", i * 100 + k * 2);
+                let ikey = format::make_internal_key(key.as_bytes(), 1, ValueType::Value);
+                builder.add(&ikey, key.as_bytes());
+            }
+            let name = file_helper::file_sstable_name(i + 1);
+            handler.add(Table::open(levels.opt.clone(), name, Some(builder), level).unwrap());
+        }
+        handler.sort();
+    }
+
+    fn get(levels: &LevelManager, level: u32, key: u64) -> Option<Slice> {
+        let key = format!("key{:08}", key);
+        let lookup_key = format::make_lookup_key(key.as_bytes(), format::MAX_SEQUENCE);
+        let (_, val) = levels
+            .search_ln_sst(level, key.as_bytes(), &lookup_key)
+            .unwrap()?;
+        Some(val)
+    }
+
+    #[test]
+    fn test_search_ln_sst() {
+        let opt = test_helper::test_options("level_search_ln_sst");
+        let levels = LevelManager::new(Arc::new(opt)).unwrap();
+        assert_eq!(get(&levels, 1, 0), None);
+
+        add_tables(&levels, 1, 10, 20);
+        for i in 0..10 {
+            for k in 0..20 {
+                let key = i * 100 + k * 2;
+                assert_eq!(
+                    get(&levels, 1, key),
+                    Some(format!("key{:08}", key).into_bytes())
+                );
+                // a key in the range of the table
+                assert_eq!(get(&levels, 1, key + 1), None);
+            }
+            // a key between tables
+            assert_eq!(get(&levels, 1, i * 100 + 60), None);
         }
+        assert_eq!(get(&levels, 1, 1000), None);
     }
 }
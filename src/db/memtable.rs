@@ -1,35 +1,57 @@
-use crate::db::options::Options;
+use crate::db::format::{self, InternalKey, ValueType};
+use crate::db::iterator::DBIterator;
+use crate::db::options::{Options, WalRecoveryMode, WriteOptions};
+use crate::db::write_batch::WriteBatch;
+use crate::error::{Error, Result};
 use crate::file::file;
 use crate::file::wal::WalFile;
 use crate::utils::file::file_helper::file_wal_name;
 use crate::utils::slice::Slice;
 use crossbeam_skiplist::SkipMap;
-use std::sync::Arc;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 pub struct MemTable {
-    pub(crate) skiplist: SkipMap<Slice, Slice>,
-    wal: WalFile,
+    pub(crate) skiplist: SkipMap<InternalKey, Slice>,
+    fid: u64,
+    // there is no wal file if the wal is disabled by options
+    wal: Mutex<Option<WalFile>>,
+    // the size of the records written, whether they are in wal or not
+    size: AtomicUsize,
+    max_seq: AtomicU64,
 }
 
 impl MemTable {
-    pub fn new(opt: Arc<Options>) -> std::io::Result<Self> {
-        opt.max_fid
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let fid = opt.max_fid.load(std::sync::atomic::Ordering::Relaxed);
-        let file_opt = file::Options {
-            file_name: file_wal_name(fid),
-            dir: opt.work_dir.clone(),
-            size: opt.memtable_size,
-            create: true,
+    pub fn new(opt: Arc<Options>) -> Result<Self> {
+        // compactions take ids at the same time, so the id is the one added
+        let fid = opt
+            .max_fid
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1;
+        let wal = if opt.disable_wal {
+            None
+        } else {
+            let file_opt = file::Options {
+                file_name: file_wal_name(fid),
+                dir: opt.work_dir.clone(),
+                size: opt.memtable_size,
+                create: true,
+            };
+            Some(WalFile::open(file_opt)?)
         };
-        let wal = WalFile::open(file_opt)?;
 
         Ok(MemTable {
             skiplist: SkipMap::new(),
-            wal,
+            fid,
+            wal: Mutex::new(wal),
+            size: AtomicUsize::new(0),
+            max_seq: AtomicU64::new(0),
         })
     }
 
-    pub fn open(opt: Arc<Options>, fid: u64) -> std::io::Result<Self> {
+    // open replays the wal file of fid, returns the memtable and the errors of
+    // the broken records dropped by the recovery mode
+    pub fn open(opt: Arc<Options>, fid: u64) -> Result<(Self, Vec<Error>)> {
         let file_opt = file::Options {
             file_name: file_wal_name(fid),
             dir: opt.work_dir.clone(),
@@ -37,37 +59,215 @@ impl MemTable {
             create: true,
         };
         let wal = WalFile::open(file_opt)?;
-        let mut memtable = MemTable {
+        let memtable = MemTable {
             skiplist: SkipMap::new(),
-            wal,
+            fid,
+            wal: Mutex::new(Some(wal)),
+            size: AtomicUsize::new(0),
+            max_seq: AtomicU64::new(0),
         };
-        memtable.replay();
-        Ok(memtable)
+        let dropped = memtable.replay(opt.wal_recovery_mode)?;
+        // the records kept by replay
+        let wal_size = memtable
+            .wal
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |wal| wal.size());
+        memtable.size.store(wal_size, Ordering::Relaxed);
+        Ok((memtable, dropped))
     }
 
-    pub fn insert(&mut self, key: &[u8], val: &[u8]) {
-        // firstly write to wal file
-        self.wal.add(key, val);
+    // write the batch to wal file as a single record and sync it if asked, then
+    // insert its entries to skiplist, the entries take the seqs from batch seq in order.
+    // the wal is skipped if it is disabled by options or by opt
+    pub fn write(&self, batch: &WriteBatch, opt: &WriteOptions) -> Result<()> {
+        // firstly write to wal file, the entries are not applied if the wal
+        // can not be synced
+        if let (Some(wal), false) = (self.wal.lock().unwrap().as_mut(), opt.disable_wal) {
+            wal.add(batch.data())?;
+            if opt.sync {
+                wal.sync()?;
+            }
+        }
+        self.size
+            .fetch_add(WalFile::record_size(batch.data()), Ordering::Relaxed);
 
         // write to skiplist
-        self.skiplist.insert(Slice::from(key), Slice::from(val));
+        self.apply(batch);
+        Ok(())
+    }
+
+    fn apply(&self, batch: &WriteBatch) {
+        for (seq, (kind, key, val)) in (batch.seq()..).zip(batch.iter()) {
+            let ikey = format::make_internal_key(key, seq, kind);
+            self.skiplist.insert(InternalKey(ikey), Slice::from(val));
+            self.max_seq.fetch_max(seq, Ordering::Relaxed);
+        }
     }
 
-    pub fn seek(&self, key: &[u8]) -> Option<Slice> {
-        self.skiplist.get(key).map(|v| v.value().clone())
+    // seek the newest entry of the key whose seq is not greater than seq
+    pub fn seek(&self, key: &[u8], seq: u64) -> Option<(ValueType, Slice)> {
+        let lookup = InternalKey(format::make_lookup_key(key, seq));
+        let entry = self.skiplist.lower_bound(Bound::Included(&lookup))?;
+        let (user_key, _, kind) = format::parse_internal_key(&entry.key().0)?;
+        if user_key != key {
+            return None;
+        }
+        Some((kind, entry.value().clone()))
     }
 
+    pub fn new_iterator(self: &Arc<Self>) -> MemTableIterator {
+        MemTableIterator {
+            mem_table: self.clone(),
+            key: Slice::new(),
+            val: Slice::new(),
+            valid: false,
+        }
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        match self.wal.lock().unwrap().as_ref() {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    // the size of the records written to the memtable, it is the size of
+    // the records in wal file unless writes skip the wal
     pub fn size(&self) -> usize {
-        self.wal.size() as usize
+        self.size.load(Ordering::Relaxed)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.fid
+    }
+
+    // the largest seq written to the memtable
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq.load(Ordering::Relaxed)
+    }
+
+    // replay applies every batch in wal file, a broken record is handled
+    // by mode, new records are written after the last record kept. returns
+    // the errors of the broken records that are dropped
+    fn replay(&self, mode: WalRecoveryMode) -> Result<Vec<Error>> {
+        let mut dropped = Vec::new();
+        let mut wal = self.wal.lock().unwrap();
+        let Some(wal) = wal.as_mut() else {
+            return Ok(dropped);
+        };
+        loop {
+            let offset = wal.size();
+            let batch = match wal.next() {
+                None => return Ok(dropped),
+                Some(record) => record.and_then(|data| {
+                    WriteBatch::from_data(&data)
+                        .ok_or_else(|| wal.corruption(offset, "invalid write batch"))
+                }),
+            };
+            let e = match batch {
+                Ok(batch) => {
+                    self.apply(&batch);
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            match mode {
+                WalRecoveryMode::FailOnBadRecord => return Err(e),
+                WalRecoveryMode::TruncateAtBadRecord => {
+                    dropped.push(e);
+                    wal.truncate(offset);
+                    return Ok(dropped);
+                }
+                WalRecoveryMode::SkipBadRecords => {
+                    dropped.push(e);
+                    // the len of the record is broken too, nothing after it can be read
+                    if wal.size() == offset {
+                        wal.truncate(offset);
+                        return Ok(dropped);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// MemTableIterator holds the memtable and the entry it stands on, every move
+// seeks the skiplist again from the entry, so writes can go on while iterating
+pub struct MemTableIterator {
+    mem_table: Arc<MemTable>,
+    key: Slice,
+    val: Slice,
+    valid: bool,
+}
+
+impl MemTableIterator {
+    fn set_entry(
+        &mut self,
+        entry: Option<crossbeam_skiplist::map::Entry<'_, InternalKey, Slice>>,
+    ) -> Option<()> {
+        match entry {
+            Some(entry) => {
+                self.key = entry.key().0.clone();
+                self.val = entry.value().clone();
+                self.valid = true;
+                Some(())
+            }
+            None => {
+                self.valid = false;
+                None
+            }
+        }
+    }
+}
+
+impl DBIterator for MemTableIterator {
+    fn seek_to_first(&mut self) {
+        let mem_table = self.mem_table.clone();
+        self.set_entry(mem_table.skiplist.front());
     }
 
-    pub fn id(&self) -> Result<u64, String> {
-        self.wal.id()
+    fn seek_to_last(&mut self) {
+        let mem_table = self.mem_table.clone();
+        self.set_entry(mem_table.skiplist.back());
     }
 
-    fn replay(&mut self) {
-        for (key, val) in &mut self.wal {
-            self.skiplist.insert(key, val);
+    fn seek(&mut self, key: &Slice) -> Option<&Slice> {
+        let mem_table = self.mem_table.clone();
+        let target = InternalKey(key.clone());
+        self.set_entry(mem_table.skiplist.lower_bound(Bound::Included(&target)))?;
+        Some(&self.key)
+    }
+
+    fn next(&mut self) -> Option<()> {
+        if !self.valid {
+            return None;
+        }
+        let mem_table = self.mem_table.clone();
+        let current = InternalKey(std::mem::take(&mut self.key));
+        self.set_entry(mem_table.skiplist.lower_bound(Bound::Excluded(&current)))
+    }
+
+    fn prev(&mut self) -> Option<()> {
+        if !self.valid {
+            return None;
         }
+        let mem_table = self.mem_table.clone();
+        let current = InternalKey(std::mem::take(&mut self.key));
+        self.set_entry(mem_table.skiplist.upper_bound(Bound::Excluded(&current)))
+    }
+
+    fn valid(&self) -> bool {
+        self.valid
+    }
+
+    fn key(&self) -> &Slice {
+        &self.key
+    }
+
+    fn val(&self) -> &Slice {
+        &self.val
     }
 }
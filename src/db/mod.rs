@@ -1,6 +1,10 @@
 pub mod iterator;
 pub mod options;
 pub mod db;
+pub mod format;
+pub mod snapshot;
+pub mod write_batch;
+mod flush;
 mod memtable;
 mod level;
 mod compact;
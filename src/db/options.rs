@@ -1,11 +1,135 @@
+use crate::error::{Error, Result};
+use crate::table::table::{BlockCache, TableCache};
 use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// WalRecoveryMode tells how to replay a wal file with a broken record, a
+// record is broken if its crc does not match, its len is out of the file, or
+// it is not a valid write batch
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalRecoveryMode {
+    // drop the broken record and all records after it, so a torn write at
+    // the end of the wal is dropped
+    #[default]
+    TruncateAtBadRecord,
+    // drop the broken record and go on with the records after it, the rest
+    // of the wal is dropped if the len of the broken record is out of the file
+    SkipBadRecords,
+    // fail to open the db with a corruption error
+    FailOnBadRecord,
+}
+
+// WalSyncMode tells when writes sync the wal to disk, a write that is not
+// synced can be lost on power failure even if it has returned
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WalSyncMode {
+    // only a write with WriteOptions::sync syncs the wal
+    #[default]
+    NoSync,
+    // every write syncs the wal
+    EveryWrite,
+    // the wal is synced once the oldest unsynced write is as old as it, by
+    // the next write or by a background thread if no write comes
+    Interval(Duration),
+    // a write syncs the wal if the bytes written since the last sync reach it
+    Bytes(u64),
+}
+
+// Compression tells how blocks of sstables are compressed, a block is stored
+// uncompressed if compressing it does not save at least 1/8 of its size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    // fast compression with a fair ratio
+    Snappy,
+    // stronger but slower compression at the level, e.g. 3
+    Zstd(i32),
+}
+
+// PrefixExtractor takes the prefix of a user key that prefix bloom filters are
+// built of, the prefix of a key depends only on the bytes it holds, so all keys
+// that start with a key share its prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrefixExtractor {
+    // the first n bytes, a shorter key has no prefix
+    Fixed(usize),
+    // the key up to and including the nth delimiter, e.g. Delimited(b'/', 2)
+    // takes "tenant/entity/", a key with fewer delimiters has no prefix
+    Delimited(u8, usize),
+}
+
+impl PrefixExtractor {
+    pub fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        match *self {
+            PrefixExtractor::Fixed(n) => key.get(..n),
+            PrefixExtractor::Delimited(delimiter, n) => {
+                let (end, _) = key
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &b)| b == delimiter)
+                    .nth(n.checked_sub(1)?)?;
+                Some(&key[..=end])
+            }
+        }
+    }
+
+    // the name is recorded with the prefix filter of a table, a filter built
+    // by another extractor is not used
+    pub fn name(&self) -> String {
+        match self {
+            PrefixExtractor::Fixed(n) => format!("fixed:{}", n),
+            PrefixExtractor::Delimited(delimiter, n) => format!("delimited:{}:{}", delimiter, n),
+        }
+    }
+}
+
+// WriteOptions are options of a single write
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    // sync the wal before the write returns, whatever the wal sync mode is
+    pub sync: bool,
+    // skip the wal, the write is lost if the db stops before its memtable is
+    // flushed, it can not be synced
+    pub disable_wal: bool,
+}
+
 #[derive(Default)]
 pub struct Options {
     pub work_dir: String,
     pub memtable_size: u64,
+    // writes stall when this many immu memtables are waiting to be flushed
+    pub max_immu_mem_tables: u32,
+    // how to replay a wal file with a broken record when db opens
+    pub wal_recovery_mode: WalRecoveryMode,
+    // when writes sync the wal, a group of writes is synced once
+    pub wal_sync_mode: WalSyncMode,
+    // no wal files are written, writes not flushed are lost when the db stops
+    pub disable_wal: bool,
     pub sstable_maxsz: u64,
     pub block_size: u64,
+    // keys in a block overlap with the key before them, every this many entries
+    // a full key is stored, which seeks in a block binary search
+    pub block_restart_interval: u32,
     pub bloom_false_positive: f64,
+    // tables get a bloom filter of key prefixes too, so prefix iterators skip
+    // tables without the prefix, it uses the rate of bloom_false_positive
+    pub prefix_extractor: Option<PrefixExtractor>,
+    // compression of blocks in sstables
+    pub compression: Compression,
+    // compression of each level, it overrides compression for the levels it
+    // holds, e.g. none for level 0 and zstd for the last level
+    pub compression_per_level: Vec<Compression>,
+    // cache of decoded blocks shared by all tables, dbs may share it too, blocks
+    // are decoded on every read if it is None
+    pub block_cache: Option<Arc<BlockCache>>,
+    // at most this many sstables are open and mapped, the least recently
+    // read are closed and opened again on demand, 0 keeps all tables open
+    pub max_open_files: u32,
+    // tables of level 0 keep their sstable, which holds the decoded index and
+    // filter, open and out of the table cache, as every lookup reads them
+    pub pin_l0_index_and_filter: bool,
 
     pub num_compactors: u32,
     pub base_level_size: u64,
@@ -16,6 +140,8 @@ pub struct Options {
     pub max_level_num: u32,
 
     pub max_fid: AtomicU64,
+    // the table cache of max_open_files, it is made by the first table
+    pub(crate) table_cache: OnceLock<Arc<TableCache>>,
 }
 
 impl Options {
@@ -23,9 +149,20 @@ impl Options {
         Options {
             work_dir: "./work_test".to_string(),
             memtable_size: 1024,
+            max_immu_mem_tables: 5,
+            wal_recovery_mode: WalRecoveryMode::TruncateAtBadRecord,
+            wal_sync_mode: WalSyncMode::NoSync,
+            disable_wal: false,
             sstable_maxsz: 1024,
             block_size: 1024,
+            block_restart_interval: 16,
             bloom_false_positive: 0.,
+            prefix_extractor: None,
+            compression: Compression::None,
+            compression_per_level: Vec::new(),
+            block_cache: Some(Arc::new(BlockCache::new(8 << 20, 16))),
+            max_open_files: 64,
+            pin_l0_index_and_filter: false,
             num_compactors: 3,
             base_level_size: 10 << 20,
             level_size_multiplier: 10,
@@ -34,7 +171,78 @@ impl Options {
             num_level_zero_tables: 15,
             max_level_num: 7,
 
-            max_fid : AtomicU64::new(0),
+            max_fid: AtomicU64::new(0),
+            table_cache: OnceLock::new(),
+        }
+    }
+
+    // the table cache bounded by max_open_files, None if tables stay open
+    pub(crate) fn table_cache(&self) -> Option<&Arc<TableCache>> {
+        if self.max_open_files == 0 {
+            return None;
+        }
+        let table_cache = self.table_cache.get_or_init(|| {
+            // every shard needs room for some tables
+            let num_shards = (self.max_open_files as usize / 64).clamp(1, 16);
+            Arc::new(TableCache::new(self.max_open_files as usize, num_shards))
+        });
+        Some(table_cache)
+    }
+
+    // the compression of tables in the level
+    pub fn compression_of(&self, level: u32) -> Compression {
+        match self.compression_per_level.get(level as usize) {
+            Some(&compression) => compression,
+            None => self.compression,
+        }
+    }
+
+    // validate checks the options before a db is opened with them
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |msg: &str| Err(Error::InvalidOptions(msg.to_string()));
+        if self.work_dir.is_empty() {
+            return invalid("work_dir is empty");
+        }
+        if self.memtable_size == 0 || self.sstable_maxsz == 0 || self.block_size == 0 {
+            return invalid("memtable_size, sstable_maxsz and block_size must be positive");
+        }
+        if self.block_restart_interval == 0 {
+            return invalid("block_restart_interval must be positive");
+        }
+        if self.max_immu_mem_tables == 0 {
+            return invalid("max_immu_mem_tables must be positive");
+        }
+        if !(0.0..1.0).contains(&self.bloom_false_positive) {
+            return invalid("bloom_false_positive must be in [0, 1)");
+        }
+        if let Some(PrefixExtractor::Fixed(0) | PrefixExtractor::Delimited(_, 0)) =
+            self.prefix_extractor
+        {
+            return invalid("prefix_extractor must take a positive len");
+        }
+        if self.max_level_num < 2 {
+            return invalid("max_level_num must be at least 2");
+        }
+        if self.num_level_zero_tables == 0
+            || self.level_size_multiplier == 0
+            || self.table_size_multiplier == 0
+        {
+            return invalid(
+                "num_level_zero_tables, level_size_multiplier and table_size_multiplier must be positive",
+            );
+        }
+        let zstd_levels = zstd::compression_level_range();
+        let compressions = std::iter::once(&self.compression).chain(&self.compression_per_level);
+        for compression in compressions {
+            if let Compression::Zstd(level) = compression {
+                if !zstd_levels.contains(level) {
+                    return invalid("zstd compression level is out of range");
+                }
+            }
+        }
+        if self.disable_wal && self.wal_sync_mode != WalSyncMode::NoSync {
+            return invalid("wal_sync_mode must be NoSync when the wal is disabled");
         }
+        Ok(())
     }
 }
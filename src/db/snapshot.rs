@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+// Snapshot pins a seq of the db, reads with it see the db as it was when
+// the snapshot was taken, compaction keeps the versions it can see until
+// it is dropped
+pub struct Snapshot {
+    seq: u64,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seq);
+    }
+}
+
+// SnapshotList holds the seqs of live snapshots and how many snapshots
+// pin each of them
+#[derive(Default)]
+pub(crate) struct SnapshotList {
+    seqs: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotList {
+    pub fn acquire(self: &Arc<Self>, seq: u64) -> Snapshot {
+        *self.seqs.lock().unwrap().entry(seq).or_insert(0) += 1;
+        Snapshot {
+            seq,
+            list: self.clone(),
+        }
+    }
+
+    fn release(&self, seq: u64) {
+        let mut seqs = self.seqs.lock().unwrap();
+        if let Some(count) = seqs.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                seqs.remove(&seq);
+            }
+        }
+    }
+
+    // seqs of live snapshots in ascending order
+    pub fn seqs(&self) -> Vec<u64> {
+        self.seqs.lock().unwrap().keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list() {
+        let list = Arc::new(SnapshotList::default());
+        let s1 = list.acquire(5);
+        let s2 = list.acquire(3);
+        let s3 = list.acquire(5);
+        assert_eq!(s1.seq(), 5);
+        assert_eq!(list.seqs(), vec![3, 5]);
+
+        drop(s1);
+        assert_eq!(list.seqs(), vec![3, 5]);
+        drop(s3);
+        assert_eq!(list.seqs(), vec![3]);
+        drop(s2);
+        assert!(list.seqs().is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use super::format::ValueType;
+use crate::utils::encodings::{decode_varint_u32, encode_varint_u32};
+use crate::utils::slice::Slice;
+
+// the header is |seq: u64| |count: u32|, the seq is the seq of the first entry,
+// the following entries take the next seqs in order
+const HEADER_SIZE: usize = 12;
+
+// WriteBatch holds puts and deletes that are applied to the db atomically,
+// its pattern is |header| |entry 1| |entry 2| ..., an entry is |kind| |key_len| |key|
+// for a delete and |kind| |key_len| |key| |val_len| |val| for a put, lens use varint encode.
+// the whole batch is written to wal file as a single record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteBatch {
+    rep: Slice,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch {
+            rep: vec![0; HEADER_SIZE],
+        }
+    }
+
+    // from_data restores a batch from its serialized data, return None if
+    // the data is not a valid batch
+    pub fn from_data(data: &[u8]) -> Option<Self> {
+        let batch = WriteBatch { rep: data.to_vec() };
+        if data.len() < HEADER_SIZE || batch.iter().count() != batch.len() {
+            return None;
+        }
+        Some(batch)
+    }
+
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, val: V) {
+        self.add(ValueType::Value, key.as_ref(), val.as_ref());
+    }
+
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.add(ValueType::Deletion, key.as_ref(), &[]);
+    }
+
+    // append adds the entries of other after the entries of the batch
+    pub fn append(&mut self, other: &WriteBatch) {
+        let count = (self.len() + other.len()) as u32;
+        self.rep[8..12].copy_from_slice(&count.to_le_bytes());
+        self.rep.extend_from_slice(&other.rep[HEADER_SIZE..]);
+    }
+
+    pub fn clear(&mut self) {
+        self.rep.clear();
+        self.rep.resize(HEADER_SIZE, 0);
+    }
+
+    // the number of entries in the batch
+    pub fn len(&self) -> usize {
+        u32::from_le_bytes(self.rep[8..12].try_into().unwrap()) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the serialized data of the batch
+    pub fn data(&self) -> &[u8] {
+        &self.rep
+    }
+
+    pub(crate) fn seq(&self) -> u64 {
+        u64::from_le_bytes(self.rep[0..8].try_into().unwrap())
+    }
+
+    pub(crate) fn set_seq(&mut self, seq: u64) {
+        self.rep[0..8].copy_from_slice(&seq.to_le_bytes());
+    }
+
+    // iter yields (kind, key, val) of entries in the order they were added
+    pub(crate) fn iter(&self) -> WriteBatchIter<'_> {
+        WriteBatchIter {
+            data: &self.rep[HEADER_SIZE..],
+        }
+    }
+
+    fn add(&mut self, kind: ValueType, key: &[u8], val: &[u8]) {
+        let count = self.len() as u32 + 1;
+        self.rep[8..12].copy_from_slice(&count.to_le_bytes());
+
+        self.rep.push(kind as u8);
+        self.rep.append(&mut encode_varint_u32(key.len() as u32));
+        self.rep.extend_from_slice(key);
+        if kind == ValueType::Value {
+            self.rep.append(&mut encode_varint_u32(val.len() as u32));
+            self.rep.extend_from_slice(val);
+        }
+    }
+}
+
+pub(crate) struct WriteBatchIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> WriteBatchIter<'a> {
+    fn read_slice(&mut self) -> Option<&'a [u8]> {
+        let (len, var_len) = decode_varint_u32(self.data)?;
+        let end = var_len + len as usize;
+        if self.data.len() < end {
+            return None;
+        }
+        let s = &self.data[var_len..end];
+        self.data = &self.data[end..];
+        Some(s)
+    }
+}
+
+impl<'a> Iterator for WriteBatchIter<'a> {
+    type Item = (ValueType, &'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&kind, rest) = self.data.split_first()?;
+        let kind = ValueType::from_u8(kind)?;
+        self.data = rest;
+        let key = self.read_slice()?;
+        let val = match kind {
+            ValueType::Value => self.read_slice()?,
+            ValueType::Deletion => &[],
+        };
+        Some((kind, key, val))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_batch() {
+        let mut batch = WriteBatch::new();
+        assert!(batch.is_empty());
+
+        batch.put("a", "1");
+        batch.delete("b");
+        batch.put("c", "");
+        batch.set_seq(100);
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.seq(), 100);
+
+        let entries: Vec<_> = batch.iter().collect();
+        assert_eq!(
+            entries,
+            vec![
+                (ValueType::Value, "a".as_bytes(), "1".as_bytes()),
+                (ValueType::Deletion, "b".as_bytes(), "".as_bytes()),
+                (ValueType::Value, "c".as_bytes(), "".as_bytes()),
+            ]
+        );
+
+        let restored = WriteBatch::from_data(batch.data()).unwrap();
+        assert_eq!(restored, batch);
+
+        // a truncated batch is not valid
+        let data = batch.data();
+        assert_eq!(WriteBatch::from_data(&data[..data.len() - 1]), None);
+        assert_eq!(WriteBatch::from_data(&data[..4]), None);
+
+        let mut other = WriteBatch::new();
+        other.delete("a");
+        let mut appended = batch.clone();
+        appended.append(&other);
+        assert_eq!(appended.len(), 4);
+        assert_eq!(appended.seq(), 100);
+        assert_eq!(
+            appended.iter().last(),
+            Some((ValueType::Deletion, "a".as_bytes(), "".as_bytes()))
+        );
+
+        batch.clear();
+        assert!(batch.is_empty());
+        assert_eq!(batch.iter().count(), 0);
+    }
+}
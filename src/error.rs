@@ -0,0 +1,93 @@
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Error is returned by every fallible operation of the db, it is Clone so an
+// error met in background, e.g. by the flush thread, can be handed to every
+// writer that waits for it
+#[derive(Debug, Clone)]
+pub enum Error {
+    // an io operation on a file failed
+    Io(Arc<io::Error>),
+    // data read from a file is broken, offset is where the broken data starts
+    Corruption {
+        file: String,
+        offset: u64,
+        msg: String,
+    },
+    // a file the db needs does not exist
+    NotFound(String),
+    // the options can not be used to open a db
+    InvalidOptions(String),
+    // an argument is not accepted, e.g. a write batch larger than a memtable
+    InvalidArgument(String),
+    // the db has been closed
+    Closed,
+    // a background task panicked or was cancelled
+    Background(String),
+}
+
+impl Error {
+    pub fn corruption<F: Into<String>, M: Into<String>>(file: F, offset: u64, msg: M) -> Self {
+        Error::Corruption {
+            file: file.into(),
+            offset,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Corruption { file, offset, msg } => {
+                write!(f, "corruption in {} at offset {}: {}", file, offset, msg)
+            }
+            Error::NotFound(msg) => write!(f, "not found: {}", msg),
+            Error::InvalidOptions(msg) => write!(f, "invalid options: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Closed => write!(f, "db is closed"),
+            Error::Background(msg) => write!(f, "background task failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(Arc::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_error() {
+        let e = Error::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert_eq!(e.to_string(), "io error: denied");
+        let source = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::PermissionDenied);
+
+        let e = Error::corruption("00001.sst", 42, "checksum mismatch");
+        assert!(matches!(e, Error::Corruption { offset: 42, .. }));
+        assert!(e.source().is_none());
+        assert_eq!(
+            e.to_string(),
+            "corruption in 00001.sst at offset 42: checksum mismatch"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::db::options::Options;
+use crate::error::{Error, Result};
 use crate::file::file;
 use crate::pb::pb;
 use crate::utils::file::file_helper::file_sstable_name_with_dir;
@@ -35,33 +36,32 @@ pub struct TableMeta {
     pub(crate) checksum: Vec<u8>,
 }
 impl ManifestFile {
-    pub fn open(opt: Arc<Options>) -> std::io::Result<ManifestFile> {
+    pub fn open(opt: Arc<Options>) -> Result<ManifestFile> {
         let manifest_path = std::path::Path::new(&opt.work_dir).join(file::MANIFSET_NAME);
-        let res = File::open(manifest_path);
+        let res = File::open(&manifest_path);
         let mut file;
-        let num;
         if let Err(e) = res {
             match e.kind() {
                 std::io::ErrorKind::NotFound => {
                     let m = Manifest::new();
-                    let (f, n) = Self::help_rwrite(&opt.work_dir, &m)?;
+                    let (f, _) = Self::help_rwrite(&opt.work_dir, &m)?;
                     file = f;
-                    num = n;
-                }
-                std::io::ErrorKind::Other => {
-                    println!("failed to open the file : {}", e);
-                    return Err(e);
                 }
                 _ => {
-                    return Err(e);
+                    return Err(e.into());
                 }
             }
         } else {
-            file = res.unwrap();
+            drop(res);
+            // reopen for appending changes after replaying
+            file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(&manifest_path)?;
         }
 
         // if open, replay the manifest
-        let manifest = Manifest::with_file(&mut file).unwrap();
+        let manifest = Manifest::with_file(&mut file)?;
         Ok(ManifestFile {
             f: Mutex::new(file),
             manifest,
@@ -69,11 +69,13 @@ impl ManifestFile {
         })
     }
 
-    pub fn add_changes(&mut self, cs: Vec<pb::ManifestChange>) -> Result<(), String> {
+    pub fn add_changes(&mut self, cs: Vec<pb::ManifestChange>) -> Result<()> {
         let cs = pb::ManifestChangeSet { changes: cs };
         let mut buf = cs.encode_to_vec();
         let mut manifest_file = self.f.lock().unwrap();
-        self.manifest.apply_change_set(cs)?;
+        self.manifest
+            .apply_change_set(cs)
+            .map_err(Error::InvalidArgument)?;
 
         let mut v = Vec::new();
         v.extend_from_slice(&(buf.len() as u32).to_le_bytes());
@@ -81,23 +83,32 @@ impl ManifestFile {
         v.extend_from_slice(&crc32.to_le_bytes());
 
         v.append(&mut buf);
-        manifest_file.write_all(&v).unwrap();
+        manifest_file.write_all(&v)?;
+        // the wal of a flushed memtable is removed after the changes are synced
+        manifest_file.sync_all()?;
+        Ok(())
+    }
+
+    // sync the changes written to the manifest file to disk
+    pub fn sync(&self) -> Result<()> {
+        self.f.lock().unwrap().sync_all()?;
         Ok(())
     }
 
-    pub fn revert(&self, set: HashSet<u64>) -> Result<(), String> {
+    pub fn revert(&self, set: HashSet<u64>) -> Result<()> {
         // set : file exists
-        for (fid, _) in &self.manifest.tables {
-            if set.contains(&fid) == false {
-                return Err(format!("file does not exist for table {}", fid));
+        for fid in self.manifest.tables.keys() {
+            if !set.contains(fid) {
+                return Err(Error::NotFound(format!(
+                    "file does not exist for table {}",
+                    fid
+                )));
             }
         }
         for fid in set {
-            if self.manifest.tables.contains_key(&fid) == false {
+            if !self.manifest.tables.contains_key(&fid) {
                 let filename = file_sstable_name_with_dir(&self.opt.work_dir, fid);
-                if let Err(e) = std::fs::remove_file(filename) {
-                    return Err(format!("remove file error, {}", e));
-                }
+                std::fs::remove_file(filename)?;
             }
         }
         Ok(())
@@ -106,11 +117,9 @@ impl ManifestFile {
         &self.manifest
     }
 
-    pub fn add_table_meta(&mut self, level: u32, t: TableMeta) -> Result<(), String> {
+    pub fn add_table_meta(&mut self, level: u32, t: TableMeta) -> Result<()> {
         let change = Manifest::new_create_change(&t.id, &level, &t.checksum);
-        let mut v = Vec::new();
-        v.push(change);
-        self.add_changes(v)
+        self.add_changes(vec![change])
     }
 
     fn help_rwrite(dir: &String, m: &Manifest) -> std::io::Result<(File, u32)> {
@@ -119,7 +128,6 @@ impl ManifestFile {
         let mut file = OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             // appen == true, set the cursor always to end
             .append(true)
             .open(&rewrite_path)?;
@@ -133,14 +141,17 @@ impl ManifestFile {
 
         let num_creations = m.tables.len();
         let changes = m.as_changes();
-        let changes_len = changes.len() as u32;
-        let c_set = pb::ManifestChangeSet { changes };
-
-        let changes_buf = c_set.encode_to_vec();
-        buf.extend_from_slice(&changes_len.to_le_bytes());
-        let checksum = crate::utils::file::calculate_checksum32(&changes_buf);
-        buf.extend_from_slice(&checksum.to_be_bytes());
-        buf.extend_from_slice(&changes_buf);
+        // the changes are written as a record like add_changes, a zero len
+        // would end the replay, so no record is written for no changes
+        if !changes.is_empty() {
+            let c_set = pb::ManifestChangeSet { changes };
+
+            let changes_buf = c_set.encode_to_vec();
+            buf.extend_from_slice(&(changes_buf.len() as u32).to_le_bytes());
+            let checksum = crate::utils::file::calculate_checksum32(&changes_buf);
+            buf.extend_from_slice(&checksum.to_le_bytes());
+            buf.extend_from_slice(&changes_buf);
+        }
 
         // debug!
         //println!("{:?}", buf);
@@ -153,7 +164,6 @@ impl ManifestFile {
         let file = OpenOptions::new()
             .create(true)
             .read(true)
-            .write(true)
             .append(true)
             .open(&manifest_path)?;
 
@@ -171,20 +181,28 @@ impl Manifest {
         }
     }
 
-    // replay_with_file apply all the changes in existed manifest file
-    pub fn with_file(file: &mut File) -> Result<Manifest, String> {
+    // replay_with_file apply all the changes in existed manifest file, a torn
+    // record at the end is taken as the end of the file
+    pub fn with_file(file: &mut File) -> Result<Manifest> {
+        let corruption =
+            |offset: usize, msg: &str| Error::corruption(file::MANIFSET_NAME, offset as u64, msg);
+
         let mut magic_buf = [0u8; 8];
-        file.read_exact(&mut magic_buf).unwrap();
-        if &magic_buf[0..4] != file::MAGIC_TEXT
-            || &magic_buf[4..8] != file::MAGIC_VERSION.to_le_bytes()
+        if file.read_exact(&mut magic_buf).is_err()
+            || magic_buf[0..4] != *file::MAGIC_TEXT
+            || magic_buf[4..8] != file::MAGIC_VERSION.to_le_bytes()
         {
-            return Err("magic not equal".to_string());
+            return Err(corruption(0, "magic not equal"));
         };
 
         let mut manifest = Manifest::new();
+        let mut offset = magic_buf.len();
         loop {
             let mut crc_buf = [0u8; 8];
-            file.read_exact(&mut crc_buf).unwrap();
+            // the end of the file, or a torn record at the end
+            if file.read_exact(&mut crc_buf).is_err() {
+                break;
+            }
 
             let data_len = u32::from_le_bytes(crc_buf[0..4].try_into().unwrap());
 
@@ -195,29 +213,36 @@ impl Manifest {
             let crc = &crc_buf[4..8];
 
             let mut data_buf = vec![0u8; data_len as usize];
-            file.read_exact(&mut data_buf).unwrap();
+            if file.read_exact(&mut data_buf).is_err() {
+                break;
+            }
 
             // debug
             //println!("{:?}", data_buf);
             //println!("{:?}",crc);
-            if crate::utils::file::verify_checksum_32(&data_buf, crc) == false {
-                return Err("checksum not equal".to_string());
+            if !crate::utils::file::verify_checksum_32(&data_buf, crc) {
+                return Err(corruption(offset, "checksum not equal"));
             }
-            let change_set = pb::ManifestChangeSet::decode(&data_buf[..]).unwrap();
+            let change_set = pb::ManifestChangeSet::decode(&data_buf[..])
+                .map_err(|e| corruption(offset, &format!("failed to decode changes, {}", e)))?;
 
-            manifest.apply_change_set(change_set).unwrap();
+            manifest
+                .apply_change_set(change_set)
+                .map_err(|e| corruption(offset, &e))?;
+            offset += crc_buf.len() + data_buf.len();
         }
 
         Ok(manifest)
     }
 
-    fn apply_change_set(&mut self, cs: pb::ManifestChangeSet) -> Result<(), String> {
+    // apply_change_set fails if a change conflicts with the manifest
+    fn apply_change_set(&mut self, cs: pb::ManifestChangeSet) -> std::result::Result<(), String> {
         for c in cs.changes {
             self.apply_change(c)?
         }
         Ok(())
     }
-    fn apply_change(&mut self, c: pb::ManifestChange) -> Result<(), String> {
+    fn apply_change(&mut self, c: pb::ManifestChange) -> std::result::Result<(), String> {
         if c.op() == pb::manifest_change::Operation::Create {
             if self.tables.contains_key(&c.id) {
                 return Err(format!("manifest invalid, table {} exists", c.id));
@@ -236,7 +261,7 @@ impl Manifest {
             self.levels[c.level as usize].insert(c.id);
             self.creations += 1;
         } else {
-            if self.tables.contains_key(&c.id) == false {
+            if !self.tables.contains_key(&c.id) {
                 return Err(format!("manifest removes non-existing table {}", c.id));
             }
             let tm = &self.tables[&c.id];
@@ -259,12 +284,12 @@ impl Manifest {
     }
 
     // create a manifest change
-    fn new_create_change(id: &u64, level: &u32, checksum: &Vec<u8>) -> pb::ManifestChange {
+    fn new_create_change(id: &u64, level: &u32, checksum: &[u8]) -> pb::ManifestChange {
         pb::ManifestChange {
             id: *id,
             op: pb::manifest_change::Operation::Create as i32,
             level: *level,
-            checksum: checksum.clone(),
+            checksum: checksum.to_vec(),
         }
     }
 }
@@ -1,19 +1,17 @@
 use super::file::Options;
+use crate::db::format;
+use crate::error::{Error, Result};
 use crate::pb::*;
-use crate::utils::file::file_helper;
+use crate::utils::filter::FILTER_VERSION;
 use crate::utils::slice::Slice;
 use memmap2::MmapMut;
 use prost::Message;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
-use std::sync::{Arc, RwLock};
+use std::fs::OpenOptions;
 use std::time::SystemTime;
 
 pub struct SSTable {
-    dir: String,
     name: String,
     f: MmapMut,
-    max_key: Slice,
     min_key: Slice,
     has_filter: bool,
     table_index: pb::TableIndex,
@@ -21,22 +19,19 @@ pub struct SSTable {
 }
 
 impl SSTable {
-    pub fn open(opt: Options) -> io::Result<Self> {
+    pub fn open(opt: Options) -> Result<Self> {
         let file = OpenOptions::new()
-            .create(true)
+            .create(opt.create)
             .write(true)
             .read(true)
             .open(std::path::Path::new(&opt.dir).join(opt.file_name.clone()))?;
 
         if opt.create {
-            file.set_len(opt.size).unwrap();
+            file.set_len(opt.size)?;
         }
-        let metadata = file.metadata()?;
         Ok(SSTable {
-            dir: opt.dir,
             name: opt.file_name,
             f: unsafe { MmapMut::map_mut(&file)? },
-            max_key: Slice::new(),
             min_key: Slice::new(),
             has_filter: true,
             table_index: pb::TableIndex::default(),
@@ -44,14 +39,15 @@ impl SSTable {
         })
     }
 
-    pub fn init(&mut self) -> Result<(), String> {
+    pub fn init(&mut self) -> Result<()> {
         let block_offset = self.init_table()?;
         self.min_key = block_offset.key.clone();
         Ok(())
     }
 
-    pub fn read(&self, pos: u32, len: u32) -> &[u8] {
-        &self.f[pos as usize..(pos + len) as usize]
+    // read the data at pos, return None if it is out of the file
+    pub fn read(&self, pos: u32, len: u32) -> Option<&[u8]> {
+        self.f.get(pos as usize..pos as usize + len as usize)
     }
 
     pub fn offsets(&self, i: u32) -> Option<pb::BlockOffset> {
@@ -66,43 +62,40 @@ impl SSTable {
         self.table_index.offsets.len() as u32 - 1
     }
 
-    // binary serach key in block
-    pub fn seek(&self, key: &[u8]) -> Option<u32> {
+    // binary serach the block that the first key greater or equal than key may be in,
+    // it is the last block whose base key is not greater than key
+    pub fn seek(&self, key: &[u8]) -> u32 {
         let found = self
             .indexs()
             .offsets
-            .binary_search_by(|offset| offset.key.cmp(&Vec::from(key)));
+            .binary_search_by(|offset| format::compare_internal_key(&offset.key, key));
         match found {
-            Ok(idx) => Some(idx as u32),
+            Ok(idx) => idx as u32,
             Err(idx) => {
                 if idx >= 1 {
-                    Some((idx - 1) as u32)
+                    (idx - 1) as u32
                 } else {
-                    None
+                    0
                 }
             }
         }
     }
 
-    pub fn set_max_key(&mut self, max_key: Slice) {
-        self.max_key = max_key;
-    }
     pub fn write_table(&mut self, data: &[u8]) {
         let len = self.f.len();
         self.f[0..len].copy_from_slice(data);
     }
 
-    pub fn id(&self) -> Result<u64, String> {
-        file_helper::fid(&self.name)
+    // sync the table written to the mmap to disk
+    pub fn sync(&self) -> Result<()> {
+        self.f.flush()?;
+        Ok(())
     }
+
     pub fn indexs(&self) -> &pb::TableIndex {
         &self.table_index
     }
 
-    pub fn max_key(&self) -> &Slice {
-        &self.max_key
-    }
-
     pub fn min_key(&self) -> &Slice {
         &self.min_key
     }
@@ -119,46 +112,57 @@ impl SSTable {
         self.created_at
     }
 
-    // delete the sst file
-    pub fn delete(&self) -> std::io::Result<()> {
-        let path = std::path::Path::new(&self.dir).join(&self.name);
-        std::fs::remove_file(path)
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    fn init_table(&mut self) -> Result<pb::BlockOffset, String> {
-        let mut read_pos = self.f.len();
+    // the footer is |index| |index len| |checksum| |checksum len|, it is read
+    // from the end of the file
+    fn init_table(&mut self) -> Result<pb::BlockOffset> {
         let data = &self.f;
+        let corruption =
+            |offset: usize, msg: &str| Error::corruption(&self.name, offset as u64, msg);
+        // read the u32 that ends at pos
+        let read_u32 = |pos: usize| {
+            let start = pos.checked_sub(4)?;
+            Some(u32::from_le_bytes(data[start..pos].try_into().unwrap()))
+        };
 
-        //debug
-        //println!("{:?}", &data[..]);
         // read checksum len from the last 4 bytes
-        let buf = &data[read_pos - 4..read_pos];
+        let mut read_pos = data.len();
+        let checksum_len =
+            read_u32(read_pos).ok_or_else(|| corruption(0, "table is too small"))? as usize;
         read_pos -= 4;
-        let checksum_len = u32::from_le_bytes(buf.try_into().unwrap());
 
         // read checksum
-        let checksum = &data[read_pos - checksum_len as usize..read_pos];
-        read_pos -= checksum_len as usize;
+        let checksum_start = read_pos
+            .checked_sub(checksum_len)
+            .ok_or_else(|| corruption(read_pos, "invalid checksum len"))?;
+        let checksum = &data[checksum_start..read_pos];
+        read_pos = checksum_start;
 
         // read index size from the footer
-        let buf = &data[read_pos - 4..read_pos];
-        let idx_len = u32::from_le_bytes(buf.try_into().unwrap());
+        let idx_len =
+            read_u32(read_pos).ok_or_else(|| corruption(0, "table is too small"))? as usize;
         read_pos -= 4;
 
         // read index
-        let idx_data = &data[read_pos - idx_len as usize..read_pos];
-        if crate::utils::file::verify_checksum(idx_data, checksum) == false {
-            return Err(format!(
-                "failed to verify checksum for table: {}",
-                self.name
-            ));
+        let idx_start = read_pos
+            .checked_sub(idx_len)
+            .ok_or_else(|| corruption(read_pos, "invalid index len"))?;
+        let idx_data = &data[idx_start..read_pos];
+        if !crate::utils::file::verify_checksum(idx_data, checksum) {
+            return Err(corruption(idx_start, "index checksum mismatch"));
         }
-        let table_index = pb::TableIndex::decode(idx_data).map_err(|e| e.to_string())?;
-        self.table_index = table_index;
-        self.has_filter = !self.table_index.bloom_filter.is_empty();
-        if !self.table_index.offsets.is_empty() {
-            return Ok(self.table_index.offsets[0].clone());
+        let table_index = pb::TableIndex::decode(idx_data)
+            .map_err(|e| corruption(idx_start, &format!("failed to decode index, {}", e)))?;
+        if table_index.offsets.is_empty() {
+            return Err(corruption(idx_start, "index has no blocks"));
         }
-        return Err("read index failed, offset is empty".to_string());
+        self.table_index = table_index;
+        // a filter hashed in another way would give false negatives
+        self.has_filter = !self.table_index.bloom_filter.is_empty()
+            && self.table_index.filter_version == FILTER_VERSION;
+        Ok(self.table_index.offsets[0].clone())
     }
 }
@@ -1,82 +1,143 @@
+use crate::error::{Error, Result};
 use crate::file::file;
-use crate::utils::encodings::*;
-use crate::utils::file::file_helper::fid_wal;
+use crate::utils::file::{calculate_checksum32, verify_checksum_32};
 use crate::utils::slice::Slice;
 use memmap2::MmapMut;
-use std::fs::OpenOptions;
-use std::io;
+use std::fs::{File, OpenOptions};
 
-fn estimate_entry_size(key: &[u8], val: &[u8]) -> u32 {
-    key.len() as u32 + val.len() as u32 + 8
-}
+// size of the len and crc before the data of a record
+pub const RECORD_HEADER_SIZE: usize = 8;
 
+// WalFile is a mmap of a wal file, the file starts with the size in options
+// and grows when a record does not fit in the rest of it, so records of any
+// size can be written. the space after the last record is zero
 pub struct WalFile {
+    file: File,
     f: MmapMut,
+    // the end of the records written, or the read position while replaying
     wrtie_at: usize,
     name: String,
+    // a record that can not be stepped over is met while replaying
+    broken: bool,
 }
 
 impl WalFile {
-    pub fn open(opt: file::Options) -> io::Result<WalFile> {
+    pub fn open(opt: file::Options) -> Result<WalFile> {
         let file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .write(true)
             .read(true)
             .open(std::path::Path::new(&opt.dir).join(opt.file_name.clone()))?;
-        file.set_len(opt.size)?;
+        // a wal that has grown is never truncated
+        if file.metadata()?.len() < opt.size {
+            file.set_len(opt.size)?;
+        }
 
         Ok(WalFile {
             f: unsafe { MmapMut::map_mut(&file)? },
+            file,
             wrtie_at: 0,
             name: opt.file_name,
+            broken: false,
         })
     }
 
-    pub fn add(&mut self, key: &[u8], val: &[u8]) {
-        let mut v = Vec::new();
-        v.append(&mut encode_varint_u32(key.len() as u32));
-        v.append(&mut encode_varint_u32(val.len() as u32));
-        v.extend_from_slice(key);
-        v.extend_from_slice(val);
+    // the size of the record of data in wal file
+    pub fn record_size(data: &[u8]) -> usize {
+        RECORD_HEADER_SIZE + data.len()
+    }
+
+    // add writes a record, its pattern is |data_len| |crc32 of data| |data|,
+    // the len and crc are u32 in little endian
+    pub fn add(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!(
+                "wal record of {} bytes is too large",
+                data.len()
+            )));
+        }
+        let end = self.wrtie_at + Self::record_size(data);
+        if end > self.f.len() {
+            self.grow(end)?;
+        }
 
-        self.f[self.wrtie_at..self.wrtie_at + v.len()].copy_from_slice(&v);
-        self.wrtie_at += v.len();
+        let header = &mut self.f[self.wrtie_at..self.wrtie_at + RECORD_HEADER_SIZE];
+        header[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&calculate_checksum32(data).to_le_bytes());
+        self.f[self.wrtie_at + RECORD_HEADER_SIZE..end].copy_from_slice(data);
+        self.wrtie_at = end;
+        Ok(())
     }
 
-    pub fn size(&self) -> u32 {
-        self.wrtie_at as u32
+    // sync the records written to the mmap to disk
+    pub fn sync(&self) -> Result<()> {
+        self.f.flush()?;
+        Ok(())
     }
 
-    pub fn id(&self) -> Result<u64, String> {
-        fid_wal(&self.name)
+    // the size of the records written
+    pub fn size(&self) -> usize {
+        self.wrtie_at
     }
 
+    // truncate drops the records from offset, the rest of the file is zeroed
+    // so records left behind are never replayed after new records
+    pub fn truncate(&mut self, offset: usize) {
+        self.f[offset..].fill(0);
+        self.wrtie_at = offset;
+        self.broken = false;
+    }
+
+    pub fn corruption(&self, offset: usize, msg: &str) -> Error {
+        Error::corruption(&self.name, offset as u64, msg)
+    }
+
+    // grow doubles the file until it holds len bytes and maps it again, the
+    // records written stay in the page cache of the file
+    fn grow(&mut self, len: usize) -> Result<()> {
+        let mut new_len = std::cmp::max(self.f.len(), RECORD_HEADER_SIZE);
+        while new_len < len {
+            new_len *= 2;
+        }
+        self.file.set_len(new_len as u64)?;
+        self.f = unsafe { MmapMut::map_mut(&self.file)? };
+        Ok(())
+    }
 }
 
 impl Iterator for WalFile {
-    type Item = (Slice, Slice);
+    type Item = Result<Slice>;
 
+    // next reads the record at the read position, it stops at the end of the
+    // written records. a record whose data does not match its crc is yielded as
+    // a corruption and the next call goes on after it, a record whose len is
+    // out of the file can not be stepped over, so the iteration stops after it
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some((key_len, var_len)) = decode_varint_u32(&self.f[self.wrtie_at..]) {
-            if key_len == 0 {
-                return None;
-            }
-            self.wrtie_at += var_len;
-            if let Some((val_len, var_len)) = decode_varint_u32(&self.f[self.wrtie_at..]) {
-                self.wrtie_at += var_len;
-                let key = Slice::from(&self.f[self.wrtie_at..self.wrtie_at + key_len as usize]);
-                self.wrtie_at += key_len as usize;
-                let val = Slice::from(&self.f[self.wrtie_at..self.wrtie_at + val_len as usize]);
-                self.wrtie_at += val_len as usize;
-                return Some((key, val));
-            } else {
-                return None;
-            }
+        if self.broken {
+            return None;
+        }
+        let offset = self.wrtie_at;
+        let header = self.f.get(offset..offset + RECORD_HEADER_SIZE)?;
+        let data_len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        if data_len == 0 {
+            return None;
+        }
+        let crc = &header[4..8];
+        let start = offset + RECORD_HEADER_SIZE;
+        let Some(data) = self.f.get(start..start + data_len) else {
+            self.broken = true;
+            return Some(Err(self.corruption(offset, "record is out of the file")));
+        };
+        self.wrtie_at = start + data_len;
+        if !verify_checksum_32(data, crc) {
+            return Some(Err(self.corruption(offset, "checksum mismatch")));
         }
-        None
+        Some(Ok(Slice::from(data)))
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::utils::test_helper;
@@ -95,15 +156,91 @@ mod tests {
         let keys = test_helper::generate_incredible_strings(10);
 
         for key in &keys {
-            wal.add(key.as_bytes(), key.as_bytes());
+            wal.add(key.as_bytes()).unwrap();
+        }
+        let wal = WalFile::open(options).unwrap();
+
+        for (count, data) in wal.enumerate() {
+            assert_eq!(&data.unwrap(), &keys[count].as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_grow() {
+        let options = file::Options {
+            size: 64,
+            file_name: "00001.wal".to_string(),
+            dir: test_helper::test_options("wal_grow").work_dir,
+            create: true,
+        };
+
+        let mut wal = WalFile::open(options.clone()).unwrap();
+        // records larger than the file and records that fill the rest of it
+        let records: Vec<Vec<u8>> = vec![vec![1; 10], vec![2; 300], vec![3; 46], vec![4; 1000]];
+        let mut size = 0;
+        for record in &records {
+            wal.add(record).unwrap();
+            size += WalFile::record_size(record);
+            assert_eq!(wal.size(), size);
         }
+        wal.sync().unwrap();
+        drop(wal);
+
+        // reopening does not truncate the grown file
+        let wal = WalFile::open(options.clone()).unwrap();
+        assert_eq!(wal.collect::<Result<Vec<_>>>().unwrap(), records);
+
+        // writes after replaying go on from the last record
+        let mut wal = WalFile::open(options.clone()).unwrap();
+        assert_eq!(wal.by_ref().count(), records.len());
+        wal.add(&[5; 5]).unwrap();
+        drop(wal);
         let wal = WalFile::open(options).unwrap();
+        assert_eq!(wal.last().unwrap().unwrap(), vec![5; 5]);
+    }
 
-        let mut count = 0;
-        for (key, val) in wal {
-            assert_eq!(&key, &keys[count].as_bytes());
-            assert_eq!(&val, &keys[count].as_bytes());
-            count += 1;
+    #[test]
+    fn test_corruption() {
+        let options = file::Options {
+            size: 64,
+            file_name: "00001.wal".to_string(),
+            dir: test_helper::test_options("wal_corruption").work_dir,
+            create: true,
+        };
+
+        let mut wal = WalFile::open(options.clone()).unwrap();
+        let records: Vec<Vec<u8>> = vec![vec![1; 10], vec![2; 20], vec![3; 30], vec![4; 40]];
+        let mut offsets = Vec::new();
+        for record in &records {
+            offsets.push(wal.size());
+            wal.add(record).unwrap();
         }
+        // flip a byte in the data of the 2nd record and the len of the 4th one
+        wal.f[offsets[1] + RECORD_HEADER_SIZE] ^= 0xff;
+        wal.f[offsets[3] + 3] ^= 0xff;
+        drop(wal);
+
+        let mut wal = WalFile::open(options.clone()).unwrap();
+        assert_eq!(wal.next().unwrap().unwrap(), records[0]);
+        match wal.next() {
+            Some(Err(Error::Corruption { file, offset, .. })) => {
+                assert_eq!(file, "00001.wal");
+                assert_eq!(offset, offsets[1] as u64);
+            }
+            _ => panic!("the 2nd record is broken"),
+        }
+        // the bad record is stepped over by its len
+        assert_eq!(wal.next().unwrap().unwrap(), records[2]);
+        assert!(matches!(wal.next(), Some(Err(Error::Corruption { .. }))));
+        assert_eq!(wal.size(), offsets[3]);
+        assert!(wal.next().is_none());
+
+        // records after the truncated offset are gone
+        wal.truncate(offsets[1]);
+        wal.add(&records[3]).unwrap();
+        drop(wal);
+        let wal = WalFile::open(options).unwrap();
+        let replayed: Vec<_> = wal.collect::<Result<_>>().unwrap();
+        assert_eq!(replayed, vec![records[0].clone(), records[3].clone()]);
     }
 }
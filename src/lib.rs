@@ -0,0 +1,9 @@
+// modules are named after the main type they hold, e.g. db::db::DB
+#![allow(clippy::module_inception)]
+
+pub mod db;
+pub mod error;
+mod file;
+mod pb;
+mod table;
+pub mod utils;
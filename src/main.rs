@@ -1,20 +1,14 @@
-mod db;
-mod file;
-mod pb;
-mod table;
-mod utils;
-
 use std::sync::Arc;
 
-use crate::db::db::DB;
-use crate::db::options::Options;
-use crate::utils::test_helper;
+use ckv::db::db::DB;
+use ckv::db::options::Options;
+use ckv::utils::test_helper;
 
 #[tokio::main]
 async fn main() {
     let opt = Options::test_new();
     test_helper::work_dir_clear(&opt.work_dir).unwrap();
-    let mut db = DB::open(Arc::new(opt)).unwrap();
+    let db = DB::open(Arc::new(opt)).unwrap();
     db.start_compacter().await;
 
     let n = 10000;
@@ -25,5 +19,5 @@ async fn main() {
         db.set(&key, &val).unwrap();
     }
 
-    loop {}
+    db.close().await.unwrap();
 }
@@ -7,6 +7,20 @@ pub struct TableIndex {
     pub bloom_filter: ::prost::alloc::vec::Vec<u8>,
     #[prost(uint32, tag = "3")]
     pub key_count: u32,
+    /// the largest seq of entries in the table
+    #[prost(uint64, tag = "4")]
+    pub max_seq: u64,
+    /// how bloom_filter hashes keys, a filter of an unknown version is not used
+    #[prost(uint32, tag = "5")]
+    pub filter_version: u32,
+    /// bloom filter of the key prefixes taken by prefix_extractor
+    #[prost(bytes = "vec", tag = "6")]
+    pub prefix_bloom_filter: ::prost::alloc::vec::Vec<u8>,
+    #[prost(string, tag = "7")]
+    pub prefix_extractor: ::prost::alloc::string::String,
+    /// how entries of blocks are encoded, version 0 blocks have no restart points
+    #[prost(uint32, tag = "8")]
+    pub block_version: u32,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BlockOffset {
@@ -16,7 +30,11 @@ pub struct BlockOffset {
     pub offset: u32,
     #[prost(uint32, tag = "3")]
     pub len: u32,
+    /// how the block is compressed, len is the compressed len
+    #[prost(enumeration = "CompressionType", tag = "4")]
+    pub compression: i32,
 }
+/// use ManifestChangeSet to encapsulation to serialize changes together
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ManifestChangeSet {
     /// a set of changes that are applied atomically
@@ -73,3 +91,32 @@ pub mod manifest_change {
         }
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CompressionType {
+    None = 0,
+    Snappy = 1,
+    Zstd = 2,
+}
+impl CompressionType {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::None => "NONE",
+            Self::Snappy => "SNAPPY",
+            Self::Zstd => "ZSTD",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "NONE" => Some(Self::None),
+            "SNAPPY" => Some(Self::Snappy),
+            "ZSTD" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
@@ -1,123 +1,384 @@
+use crate::db::format;
 use crate::db::iterator::DBIterator;
 use crate::db::options::Options;
+use crate::error::{Error, Result};
 use crate::file::file;
 use crate::file::sstable::SSTable;
-use crate::table::table_builder::{BlockIterator, TableBuilder};
-use crate::utils::filter::Filter;
+use crate::pb::pb::{BlockOffset, CompressionType};
+use crate::table::table_builder::{BlockIterator, DecodedBlock, TableBuilder, BLOCK_VERSION};
+use crate::utils::cache::LruCache;
+use crate::utils::compress;
+use crate::utils::file::file_helper;
+use crate::utils::filter::{Filter, FILTER_VERSION};
 use crate::utils::slice::Slice;
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 
+// BlockCache caches decoded blocks by the cache id of their table and block
+// offset, every opened table gets a new cache id, so tables of dbs that share
+// the cache, or a table that reuses the file id of a dropped one, never collide
+pub type BlockCache = LruCache<(u64, u32), DecodedBlock>;
+
+// BloomStats counts how the bloom filters of tables answer point lookups, a
+// lookup of a table without a filter is not counted
+#[derive(Default)]
+pub struct BloomStats {
+    useful: AtomicU64,
+    true_positive: AtomicU64,
+    false_positive: AtomicU64,
+}
+
+impl BloomStats {
+    // the lookups the filter said no to, they read no block
+    pub fn useful(&self) -> u64 {
+        self.useful.load(Ordering::Relaxed)
+    }
+
+    // the lookups the filter passed that found the key
+    pub fn true_positive(&self) -> u64 {
+        self.true_positive.load(Ordering::Relaxed)
+    }
+
+    // the lookups the filter passed that did not find the key, a key with no
+    // entry visible to the lookup is counted too
+    pub fn false_positive(&self) -> u64 {
+        self.false_positive.load(Ordering::Relaxed)
+    }
+}
+
+// TableCache caches open sstables by name, an sstable missing in it is opened
+// again when it is read
+pub type TableCache = LruCache<String, SSTable>;
+
+// Table holds the metadata of an sstable, the sstable itself is kept open or
+// opened on demand through the table cache
 pub struct Table {
-    sstable: SSTable,
+    opt: Arc<Options>,
+    name: String,
+    sstable: TableHandle,
+    min_key: Slice,
+    max_key: Slice,
+    size: u64,
+    max_seq: u64,
+    create_at: SystemTime,
     ref_count: AtomicU32,
-    //opt: Arc<Options>,
+    // the file is deleted when the last user of a table that left its level is gone
+    obsolete: AtomicBool,
+    // the block cache and the cache id of the table in it
+    cache: Option<(Arc<BlockCache>, u64)>,
+}
+
+enum TableHandle {
+    // the sstable stays open as long as the table
+    Open(Arc<SSTable>),
+    // the sstable is in the table cache, or is opened again when it is evicted
+    Cached(Arc<TableCache>),
 }
 
 impl Table {
+    // open the table of the level, a table of level 0 keeps its sstable open
+    // if pin_l0_index_and_filter is set
     pub fn open(
         opt: Arc<Options>,
         name: String,
         table_builder: Option<TableBuilder>,
-    ) -> std::io::Result<Table> {
+        level: u32,
+    ) -> Result<Table> {
         let mut table;
         if let Some(mut builder) = table_builder {
             table = builder.flush(name.clone())?;
+            table.init()?;
         } else {
-            let file_options = file::Options {
-                size: opt.sstable_maxsz,
-                file_name: name.clone(),
-                dir: opt.work_dir.clone(),
-                create: false,
-            };
-            table = SSTable::open(file_options)?;
+            table = Self::open_sstable(&opt, &name)?;
         }
 
-        table.init().unwrap();
-
+        let cache = opt
+            .block_cache
+            .as_ref()
+            .map(|cache| (cache.clone(), cache.new_id()));
+        let table = Arc::new(table);
         let mut res = Table {
-            sstable: table,
+            opt: opt.clone(),
+            name,
+            sstable: TableHandle::Open(table.clone()),
+            min_key: table.min_key().clone(),
+            max_key: Slice::new(),
+            size: table.size(),
+            max_seq: table.indexs().max_seq,
+            create_at: table.get_create_at(),
             ref_count: AtomicU32::new(1),
+            obsolete: AtomicBool::new(false),
+            cache,
         };
 
-        let mut iter = res.new_iterator();
-        iter.seek_to_last();
+        // init makes sure the table has blocks
+        let last_block = table.offsets(table.last_offset_idx()).unwrap();
+        let mut bi = res.block_iterator(&table, &last_block)?;
+        bi.seek_to_last();
+        res.max_key = bi.key().clone();
 
-        res.sstable.set_max_key(iter.key().clone());
+        let pinned = level == 0 && opt.pin_l0_index_and_filter;
+        match opt.table_cache() {
+            Some(table_cache) if !pinned => {
+                table_cache.insert(res.name.clone(), table, 1);
+                res.sstable = TableHandle::Cached(table_cache.clone());
+            }
+            _ => {}
+        }
 
         Ok(res)
     }
 
-    // incre ref count of table
-    pub fn incr_ref(&self) {
-        self.ref_count
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    fn open_sstable(opt: &Options, name: &str) -> Result<SSTable> {
+        let file_options = file::Options {
+            size: opt.sstable_maxsz,
+            file_name: name.to_string(),
+            dir: opt.work_dir.clone(),
+            create: false,
+        };
+        let mut table = SSTable::open(file_options)?;
+        table.init()?;
+        Ok(table)
     }
 
-    // decre ref count of table, if count is 0, delete file
-    pub fn decr_ref(&self) -> std::io::Result<()> {
+    // sstable returns the open sstable of the table, it is opened and put in
+    // the table cache if it has been evicted
+    fn sstable(&self) -> Result<Arc<SSTable>> {
+        let table_cache = match &self.sstable {
+            TableHandle::Open(sstable) => return Ok(sstable.clone()),
+            TableHandle::Cached(table_cache) => table_cache,
+        };
+        if let Some(sstable) = table_cache.get(&self.name) {
+            return Ok(sstable);
+        }
+        let sstable = Arc::new(Self::open_sstable(&self.opt, &self.name)?);
+        table_cache.insert(self.name.clone(), sstable.clone(), 1);
+        Ok(sstable)
+    }
+
+    // decre ref count of table, if count is 0, the file is deleted once the
+    // table is dropped, so iterators that hold the table keep working
+    pub fn decr_ref(&self) -> Result<()> {
         self.ref_count
             .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
 
         let ref_count = self.ref_count.load(std::sync::atomic::Ordering::Relaxed);
         if ref_count == 0 {
-            self.sstable.delete()?
+            self.obsolete
+                .store(true, std::sync::atomic::Ordering::Relaxed);
         }
         Ok(())
     }
 
-    pub fn new_iterator(&self) -> TableIterator {
+    // table iterator holds the table, so it keeps working after the table
+    // is removed from its level
+    pub fn new_iterator(self: &Arc<Self>) -> TableIterator {
         TableIterator {
-            table: &self,
+            table: self.clone(),
+            sstable: None,
             block_pos: 0,
             bi: BlockIterator::default(),
+            valid: false,
+            err: None,
         }
     }
 
+    // get the newest entry of the user key that is visible to the lookup key,
+    // return the internal key and val of the entry. the bloom filter is checked
+    // before any block is read, and its answer is counted in stats. a table
+    // that fails to open or a block that can not be read is an error, not a
+    // missing key
+    pub fn get(
+        self: &Arc<Self>,
+        key: &[u8],
+        lookup_key: &[u8],
+        stats: &BloomStats,
+    ) -> Result<Option<(Slice, Slice)>> {
+        let sstable = self.sstable()?;
+        let has_filter = sstable.has_bloom_filter();
+        if !Self::may_contain_key(&sstable, key) {
+            stats.useful.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        let mut iter = self.new_iterator();
+        iter.sstable = Some(sstable);
+        let found = match iter.seek(lookup_key) {
+            Some(ikey) if format::user_key(ikey) == key => Some((ikey.clone(), iter.val().clone())),
+            _ => None,
+        };
+        iter.status()?;
+        if has_filter {
+            let counter = match found {
+                Some(_) => &stats.true_positive,
+                None => &stats.false_positive,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(found)
+    }
+
     // to filter a key search if filter exists
-    pub fn filter_my_contain_key(&self, key: &[u8]) -> bool {
-        let filter = Filter::with_filter(&self.sstable.indexs().bloom_filter);
-        if self.sstable.has_bloom_filter() {
+    #[cfg(test)]
+    pub fn filter_my_contain_key(&self, key: &[u8]) -> Result<bool> {
+        let sstable = self.sstable()?;
+        Ok(Self::may_contain_key(&sstable, key))
+    }
+
+    // may_contain_prefix tells if the table may hold user keys that start with
+    // prefix, by its key range and its prefix filter
+    pub fn may_contain_prefix(&self, prefix: &[u8]) -> Result<bool> {
+        let min_key = format::user_key(&self.min_key);
+        let max_key = format::user_key(&self.max_key);
+        if max_key < prefix || (min_key > prefix && !min_key.starts_with(prefix)) {
+            return Ok(false);
+        }
+        // all keys that start with prefix share the prefix the extractor takes
+        let Some(extractor) = &self.opt.prefix_extractor else {
+            return Ok(true);
+        };
+        let Some(key_prefix) = extractor.prefix(prefix) else {
+            return Ok(true);
+        };
+        let sstable = self.sstable()?;
+        let index = sstable.indexs();
+        if index.filter_version != FILTER_VERSION || index.prefix_extractor != extractor.name() {
+            return Ok(true);
+        }
+        Ok(Filter::with_filter(&index.prefix_bloom_filter).may_contain_key(key_prefix))
+    }
+
+    fn may_contain_key(sstable: &SSTable, key: &[u8]) -> bool {
+        let filter = Filter::with_filter(&sstable.indexs().bloom_filter);
+        if sstable.has_bloom_filter() {
             filter.may_contain_key(key)
         } else {
             true
         }
     }
 
-    pub fn id(&self) -> Result<u64, String> {
-        self.sstable.id()
+    pub fn id(&self) -> Result<u64> {
+        file_helper::fid(&self.name)
     }
     pub fn size(&self) -> u64 {
-        self.sstable.size()
+        self.size
     }
     pub fn min_key(&self) -> &Slice {
-        self.sstable.min_key()
+        &self.min_key
     }
     pub fn max_key(&self) -> &Slice {
-        self.sstable.max_key()
+        &self.max_key
+    }
+    // the largest seq of entries in the table
+    pub fn max_seq(&self) -> u64 {
+        self.max_seq
     }
     pub fn create_at(&self) -> SystemTime {
-        self.sstable.get_create_at()
+        self.create_at
+    }
+
+    // block_iterator reads the block from the block cache, a block missing in
+    // it is decoded and cached
+    fn block_iterator(&self, sstable: &SSTable, offsets: &BlockOffset) -> Result<BlockIterator> {
+        let Some((cache, id)) = &self.cache else {
+            let block = Self::decode_block(sstable, offsets)?;
+            return Ok(BlockIterator::new(Arc::new(block)));
+        };
+        let key = (*id, offsets.offset);
+        let block = match cache.get(&key) {
+            Some(block) => block,
+            None => {
+                let block = Arc::new(Self::decode_block(sstable, offsets)?);
+                cache.insert(key, block.clone(), block.size());
+                block
+            }
+        };
+        Ok(BlockIterator::new(block))
+    }
+
+    fn decode_block(sstable: &SSTable, offsets: &BlockOffset) -> Result<DecodedBlock> {
+        let corruption =
+            || Error::corruption(sstable.name(), offsets.offset as u64, "invalid block");
+        let data = sstable
+            .read(offsets.offset, offsets.len)
+            .ok_or_else(corruption)?;
+        let decompressed;
+        let data = match CompressionType::try_from(offsets.compression) {
+            Ok(CompressionType::None) => data,
+            Ok(kind) => {
+                decompressed = compress::decompress(kind, data).ok_or_else(corruption)?;
+                &decompressed
+            }
+            Err(_) => return Err(corruption()),
+        };
+
+        //debug
+        //println!("{:?}", data);
+        match sstable.indexs().block_version {
+            0 => DecodedBlock::decode_v0(data, &offsets.key).ok_or_else(corruption),
+            BLOCK_VERSION => DecodedBlock::decode(data).ok_or_else(corruption),
+            _ => Err(Error::corruption(
+                sstable.name(),
+                offsets.offset as u64,
+                "unknown block version",
+            )),
+        }
     }
 }
 
-pub struct TableIterator<'a> {
-    table: &'a Table,
+impl Drop for Table {
+    fn drop(&mut self) {
+        if !self.obsolete.load(std::sync::atomic::Ordering::Relaxed) {
+            return;
+        }
+        // the blocks of a deleted table are never read again
+        if let Some((cache, id)) = &self.cache {
+            if let Ok(sstable) = self.sstable() {
+                for i in 0..=sstable.last_offset_idx() {
+                    if let Some(offsets) = sstable.offsets(i) {
+                        cache.remove(&(*id, offsets.offset));
+                    }
+                }
+            }
+        }
+        if let TableHandle::Cached(table_cache) = &self.sstable {
+            table_cache.remove(&self.name);
+        }
+        // a file failed to delete is not in the manifest, it is removed when
+        // the db opens again
+        let path = std::path::Path::new(&self.opt.work_dir).join(&self.name);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+pub struct TableIterator {
+    table: Arc<Table>,
+    // the sstable is opened by the first seek, so an idle iterator holds no file
+    sstable: Option<Arc<SSTable>>,
     block_pos: u32,
-    bi: BlockIterator<'a>,
+    bi: BlockIterator,
+    valid: bool,
+    // the error that made the iterator invalid, the table is not read as
+    // shorter than it is
+    err: Option<Error>,
 }
 
-impl<'a> TableIterator<'a> {
+impl TableIterator {
     pub fn seek_to_first(&mut self) {
-        self.set_block(0);
-        self.bi.seek_to_first();
+        self.valid = self.set_block(0).is_some();
+        if self.valid {
+            self.bi.seek_to_first();
+        }
     }
 
     pub fn seek_to_last(&mut self) {
-        let idx = self.table.sstable.last_offset_idx();
-        self.set_block(idx);
-        self.bi.seek_to_last();
+        self.valid = match self.sstable() {
+            Some(sstable) => self.set_block(sstable.last_offset_idx()).is_some(),
+            None => false,
+        };
+        if self.valid {
+            self.bi.seek_to_last();
+        }
     }
 
     pub fn key(&self) -> &Slice {
@@ -128,48 +389,103 @@ impl<'a> TableIterator<'a> {
         self.bi.val()
     }
 
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn status(&self) -> Result<()> {
+        match &self.err {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
     pub fn next(&mut self) -> Option<()> {
         match self.bi.next() {
-            Some(()) => return Some(()),
+            Some(()) => Some(()),
             None => {
-                self.set_block(self.block_pos + 1)?;
+                if self.set_block(self.block_pos + 1).is_none() {
+                    self.valid = false;
+                    return None;
+                }
                 self.bi.seek_to_first();
-                return Some(());
+                Some(())
             }
         }
     }
 
-    pub fn seek(&mut self, key: &[u8]) -> Option<&Slice> {
-        if self.table.filter_my_contain_key(key) == false {
-            return None;
+    pub fn prev(&mut self) -> Option<()> {
+        match self.bi.prev() {
+            Some(()) => Some(()),
+            None => {
+                if self.block_pos == 0 || self.set_block(self.block_pos - 1).is_none() {
+                    self.valid = false;
+                    return None;
+                }
+                self.bi.seek_to_last();
+                Some(())
+            }
         }
-        let block_idx = self.table.sstable.seek(key)?;
-        self.set_block(block_idx)?;
-        self.bi.seek(key)
     }
 
-    fn set_block(&mut self, idx: u32) -> Option<()> {
-        let offsets = self.table.sstable.offsets(idx)?;
-
-        let data = self.table.sstable.read(offsets.offset, offsets.len);
+    // seek to the first entry whose internal key is greater or equal than key
+    pub fn seek(&mut self, key: &[u8]) -> Option<&Slice> {
+        self.valid = self.seek_block(key).is_some();
+        if self.valid {
+            Some(self.bi.key())
+        } else {
+            None
+        }
+    }
 
-        //debug
-        //println!("{:?}", data);
-        let base_key = offsets.key;
+    fn seek_block(&mut self, key: &[u8]) -> Option<()> {
+        let block_idx = self.sstable()?.seek(key);
+        self.set_block(block_idx)?;
+        if self.bi.seek(key).is_none() {
+            // all keys in the block are less than key, the next block starts with the answer
+            self.set_block(block_idx + 1)?;
+            self.bi.seek_to_first();
+        }
+        Some(())
+    }
 
-        self.bi = BlockIterator::new(data, &base_key);
-        self.bi.init().unwrap();
+    // the sstable of the table, a table that fails to open makes the iterator
+    // invalid with the error in status
+    fn sstable(&mut self) -> Option<Arc<SSTable>> {
+        if self.sstable.is_none() {
+            match self.table.sstable() {
+                Ok(sstable) => self.sstable = Some(sstable),
+                Err(e) => self.err = Some(e),
+            }
+        }
+        self.sstable.clone()
+    }
 
+    // a broken block ends the iteration, the error is kept in status
+    fn set_block(&mut self, idx: u32) -> Option<()> {
+        let sstable = self.sstable()?;
+        let offsets = sstable.offsets(idx)?;
+        match self.table.block_iterator(&sstable, &offsets) {
+            Ok(bi) => self.bi = bi,
+            Err(e) => {
+                self.err = Some(e);
+                return None;
+            }
+        }
         self.block_pos = idx;
         Some(())
     }
 }
 
-impl<'a> DBIterator for TableIterator<'a> {
+impl DBIterator for TableIterator {
     fn seek_to_first(&mut self) {
         self.seek_to_first();
     }
 
+    fn seek_to_last(&mut self) {
+        self.seek_to_last();
+    }
+
     fn seek(&mut self, key: &Slice) -> Option<&Slice> {
         self.seek(key)
     }
@@ -178,6 +494,14 @@ impl<'a> DBIterator for TableIterator<'a> {
         self.next()
     }
 
+    fn prev(&mut self) -> Option<()> {
+        self.prev()
+    }
+
+    fn valid(&self) -> bool {
+        self.valid()
+    }
+
     fn key(&self) -> &Slice {
         self.key()
     }
@@ -185,14 +509,22 @@ impl<'a> DBIterator for TableIterator<'a> {
     fn val(&self) -> &Slice {
         self.val()
     }
+
+    fn status(&self) -> Result<()> {
+        self.status()
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::format::ValueType;
+    use crate::db::options::Compression;
     use crate::utils::test_helper;
+    use prost::Message;
     #[test]
     fn test_table() {
-        let option = Options::test_new();
+        let option = test_helper::test_options("table");
         let option = Arc::new(option);
 
         let mut table_builder = TableBuilder::new(option.clone());
@@ -200,38 +532,514 @@ mod tests {
         let num = 1000;
         let keys = test_helper::generate_incredible_strings(num);
 
-        for key in keys {
-            table_builder.add(&key.as_bytes(), &key.as_bytes());
-            println!("key : {}, value : {}", key, key);
+        for (i, key) in keys.iter().enumerate() {
+            let ikey = format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+            table_builder.add(&ikey, key.as_bytes());
         }
 
-        let sstable = table_builder.flush("001".to_string()).unwrap();
+        table_builder.flush("001".to_string()).unwrap();
 
-        let table = Table::open(option.clone(), "001".to_string(), None).unwrap();
+        let table = Arc::new(Table::open(option.clone(), "001".to_string(), None, 0).unwrap());
+        assert_eq!(table.max_seq(), num as u64);
 
         let mut iter = table.new_iterator();
         iter.seek_to_first();
 
-        let value = iter.seek("abc".as_bytes());
+        let value = iter.seek(&format::make_lookup_key(
+            "abc".as_bytes(),
+            format::MAX_SEQUENCE,
+        ));
+        assert_eq!(format::user_key(value.unwrap()), "abc".as_bytes());
         println!(
             "key : {}, value : {}",
-            test_helper::display(iter.key()).unwrap(),
+            test_helper::display(format::user_key(iter.key())).unwrap(),
             test_helper::display(iter.val()).unwrap()
         );
 
+        let mut count = 1;
         while let Some(()) = iter.next() {
-            println!(
-                "key : {}, value : {}",
-                test_helper::display(iter.key()).unwrap(),
-                test_helper::display(iter.val()).unwrap()
-            );
+            assert_eq!(format::user_key(iter.key()), keys[count].as_bytes());
+            count += 1;
         }
-        let value = iter.seek("abj".as_bytes());
-        println!("{}", test_helper::display(&value.unwrap()).unwrap());
-        println!(
-            "key : {}, value : {}",
-            test_helper::display(iter.key()).unwrap(),
-            test_helper::display(iter.val()).unwrap()
+        assert_eq!(count, num);
+
+        // iterate in reverse across blocks
+        iter.seek_to_last();
+        let mut count = num;
+        while iter.valid() {
+            count -= 1;
+            assert_eq!(format::user_key(iter.key()), keys[count].as_bytes());
+            iter.prev();
+        }
+        assert_eq!(count, 0);
+
+        let value = iter.seek(&format::make_lookup_key(
+            "abj".as_bytes(),
+            format::MAX_SEQUENCE,
+        ));
+        assert_eq!(format::user_key(value.unwrap()), "abj".as_bytes());
+        assert_eq!(iter.val(), &"abj".as_bytes().to_vec());
+
+        // seek to a missing key lands on the next key
+        let value = iter.seek(&format::make_lookup_key(
+            "abja".as_bytes(),
+            format::MAX_SEQUENCE,
+        ));
+        assert_eq!(format::user_key(value.unwrap()), "abk".as_bytes());
+
+        // the entry is not visible to an older seq
+        let stats = BloomStats::default();
+        let lookup = format::make_lookup_key("abj".as_bytes(), 1);
+        assert_eq!(table.get("abj".as_bytes(), &lookup, &stats).unwrap(), None);
+        let lookup = format::make_lookup_key("abj".as_bytes(), format::MAX_SEQUENCE);
+        let (_, val) = table
+            .get("abj".as_bytes(), &lookup, &stats)
+            .unwrap()
+            .unwrap();
+        assert_eq!(val, "abj".as_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_table_compression() {
+        let mut option = test_helper::test_options("table_compression");
+        option.block_size = 4096;
+        option.compression = Compression::Snappy;
+        option.compression_per_level = vec![Compression::None, Compression::Zstd(3)];
+        let option = Arc::new(option);
+
+        let keys = test_helper::generate_incredible_strings(1000);
+        let val = "{\"name\": \"val\", \"tags\": [\"a\", \"b\"]}".repeat(4);
+        let mut sizes = Vec::new();
+        for (level, kind) in [
+            (0, CompressionType::None),
+            (1, CompressionType::Zstd),
+            (2, CompressionType::Snappy),
+        ] {
+            let mut table_builder = TableBuilder::with_level(option.clone(), level);
+            for (i, key) in keys.iter().enumerate() {
+                let ikey =
+                    format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+                table_builder.add(&ikey, val.as_bytes());
+            }
+            let name = format!("00{}", level + 1);
+            table_builder.flush(name.clone()).unwrap();
+
+            let table = Arc::new(Table::open(option.clone(), name, None, 0).unwrap());
+            let offsets = table.sstable().unwrap().offsets(0).unwrap();
+            assert_eq!(offsets.compression, kind as i32);
+            sizes.push(table.size());
+
+            // blocks are decompressed when they are read
+            let mut iter = table.new_iterator();
+            iter.seek_to_first();
+            let mut count = 0;
+            while iter.valid() {
+                assert_eq!(format::user_key(iter.key()), keys[count].as_bytes());
+                assert_eq!(iter.val(), val.as_bytes());
+                count += 1;
+                iter.next();
+            }
+            assert_eq!(count, keys.len());
+        }
+        assert!(sizes[1] < sizes[0] / 4 && sizes[2] < sizes[0] / 2);
+    }
+
+    #[test]
+    fn test_table_block_cache() {
+        let mut option = test_helper::test_options("table_block_cache");
+        let cache = Arc::new(BlockCache::new(1 << 20, 4));
+        option.block_cache = Some(cache.clone());
+        let option = Arc::new(option);
+
+        let mut table_builder = TableBuilder::new(option.clone());
+        let keys = test_helper::generate_incredible_strings(1000);
+        for (i, key) in keys.iter().enumerate() {
+            let ikey = format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+            table_builder.add(&ikey, key.as_bytes());
+        }
+        table_builder.flush("00001.sst".to_string()).unwrap();
+        // opening the table reads the last block
+        let (hits, misses) = (cache.hits(), cache.misses());
+        let table =
+            Arc::new(Table::open(option.clone(), "00001.sst".to_string(), None, 0).unwrap());
+        let num_blocks = table.sstable().unwrap().last_offset_idx() as u64 + 1;
+        assert!(num_blocks > 1);
+
+        // the first scan decodes the blocks, the second finds them in cache
+        for _ in 0..2 {
+            let mut iter = table.new_iterator();
+            iter.seek_to_first();
+            let mut count = 0;
+            while iter.valid() {
+                assert_eq!(format::user_key(iter.key()), keys[count].as_bytes());
+                count += 1;
+                iter.next();
+            }
+            assert_eq!(count, keys.len());
+        }
+        assert_eq!(cache.misses() - misses, num_blocks);
+        // the last block is cached when the table opens
+        assert_eq!(cache.hits() - hits, num_blocks + 1);
+        assert_eq!(cache.len() as u64, num_blocks);
+
+        // blocks of a deleted table are removed from the cache once it is dropped
+        table.decr_ref().unwrap();
+        assert_eq!(cache.len() as u64, num_blocks);
+        drop(table);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.usage(), 0);
+    }
+
+    #[test]
+    fn test_table_cache() {
+        let mut option = test_helper::test_options("table_cache");
+        option.max_open_files = 2;
+        let option = Arc::new(option);
+        let table_cache = option.table_cache().unwrap().clone();
+
+        let keys = test_helper::generate_incredible_strings(100);
+        let mut tables = Vec::new();
+        for id in 1..=5 {
+            let mut table_builder = TableBuilder::new(option.clone());
+            for (i, key) in keys.iter().enumerate() {
+                let ikey =
+                    format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+                table_builder.add(&ikey, format!("{}{}", key, id).as_bytes());
+            }
+            let name = format!("0000{}.sst", id);
+            let table = Table::open(option.clone(), name, Some(table_builder), 0).unwrap();
+            tables.push(Arc::new(table));
+            assert!(table_cache.len() <= 2);
+        }
+
+        // evicted tables are opened again and keep their metadata resident
+        for _ in 0..2 {
+            for (id, table) in tables.iter().enumerate() {
+                assert_eq!(format::user_key(table.min_key()), keys[0].as_bytes());
+                assert_eq!(format::user_key(table.max_key()), keys[99].as_bytes());
+                let lookup = format::make_lookup_key(keys[50].as_bytes(), format::MAX_SEQUENCE);
+                let (_, val) = table
+                    .get(keys[50].as_bytes(), &lookup, &BloomStats::default())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(val, format!("{}{}", keys[50], id + 1).into_bytes());
+                assert!(table_cache.len() <= 2);
+            }
+        }
+
+        // an iterator holds its sstable after it is evicted
+        let mut iter = tables[0].new_iterator();
+        iter.seek_to_first();
+        for table in &tables[1..] {
+            table.filter_my_contain_key(keys[0].as_bytes()).unwrap();
+        }
+        let mut count = 0;
+        while iter.valid() {
+            count += 1;
+            iter.next();
+        }
+        assert_eq!(count, keys.len());
+
+        // a table that fails to open again is an error, not an empty table
+        for table in &tables[1..] {
+            table.filter_my_contain_key(keys[0].as_bytes()).unwrap();
+        }
+        let path = std::path::Path::new(&option.work_dir).join("00001.sst");
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lookup = format::make_lookup_key(keys[50].as_bytes(), format::MAX_SEQUENCE);
+        assert!(tables[0]
+            .get(keys[50].as_bytes(), &lookup, &BloomStats::default())
+            .is_err());
+        assert!(tables[0].filter_my_contain_key(keys[0].as_bytes()).is_err());
+        let mut iter = tables[0].new_iterator();
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert!(matches!(iter.status(), Err(Error::Io(_))));
+        assert!(!path.exists());
+        std::fs::write(&path, data).unwrap();
+
+        // the file of an obsolete table is deleted when it is dropped
+        let table = tables.pop().unwrap();
+        table.decr_ref().unwrap();
+        let path = std::path::Path::new(&option.work_dir).join("00005.sst");
+        assert!(path.exists());
+        drop(table);
+        assert!(!path.exists());
+        assert!(table_cache.get(&"00005.sst".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_table_block_cache_ids() {
+        let cache = Arc::new(BlockCache::new(1 << 20, 4));
+        let keys = test_helper::generate_incredible_strings(100);
+        let open = |option: &Arc<Options>, val: &str| {
+            let mut table_builder = TableBuilder::new(option.clone());
+            for (i, key) in keys.iter().enumerate() {
+                let ikey =
+                    format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+                table_builder.add(&ikey, val.as_bytes());
+            }
+            let name = "00001.sst".to_string();
+            Arc::new(Table::open(option.clone(), name, Some(table_builder), 0).unwrap())
+        };
+        let get = |table: &Arc<Table>| {
+            let lookup = format::make_lookup_key(keys[50].as_bytes(), format::MAX_SEQUENCE);
+            let stats = BloomStats::default();
+            let (_, val) = table
+                .get(keys[50].as_bytes(), &lookup, &stats)
+                .unwrap()
+                .unwrap();
+            val
+        };
+
+        // dbs that share the cache have tables of the same file id
+        let mut dbs = Vec::new();
+        for name in ["table_cache_ids_a", "table_cache_ids_b"] {
+            let mut option = test_helper::test_options(name);
+            option.block_cache = Some(cache.clone());
+            dbs.push(Arc::new(option));
+        }
+        let a = open(&dbs[0], "a");
+        let b = open(&dbs[1], "b");
+        assert_eq!(get(&a), b"a");
+        assert_eq!(get(&b), b"b");
+
+        // a table that reuses the file id of a dropped table does not read
+        // the blocks of it
+        drop(a);
+        let a = open(&dbs[0], "c");
+        assert_eq!(get(&a), b"c");
+    }
+
+    #[test]
+    fn test_table_pin_l0() {
+        let mut option = test_helper::test_options("table_pin_l0");
+        option.max_open_files = 1;
+        option.pin_l0_index_and_filter = true;
+        let option = Arc::new(option);
+        let table_cache = option.table_cache().unwrap().clone();
+
+        let keys = test_helper::generate_incredible_strings(100);
+        let mut tables = Vec::new();
+        for id in 1..=4 {
+            let mut table_builder = TableBuilder::new(option.clone());
+            for (i, key) in keys.iter().enumerate() {
+                let ikey =
+                    format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+                table_builder.add(&ikey, key.as_bytes());
+            }
+            let name = format!("0000{}.sst", id);
+            let level = id % 2;
+            let table = Table::open(option.clone(), name, Some(table_builder), level).unwrap();
+            tables.push((level, Arc::new(table)));
+        }
+
+        // tables of level 0 keep their index and filter, others are evicted
+        for _ in 0..2 {
+            for (level, table) in &tables {
+                assert!(table.filter_my_contain_key(keys[0].as_bytes()).unwrap());
+                let cached = table_cache.get(&table.name).is_some();
+                assert_eq!(matches!(table.sstable, TableHandle::Open(_)), *level == 0);
+                assert_eq!(cached, *level != 0);
+            }
+        }
+        assert_eq!(table_cache.len(), 1);
+    }
+
+    #[test]
+    fn test_table_filter_version() {
+        let mut option = test_helper::test_options("table_filter_version");
+        option.bloom_false_positive = 0.01;
+        let option = Arc::new(option);
+
+        let mut table_builder = TableBuilder::new(option.clone());
+        let keys = test_helper::generate_incredible_strings(100);
+        for (i, key) in keys.iter().enumerate() {
+            let ikey = format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+            table_builder.add(&ikey, key.as_bytes());
+        }
+        let table = Table::open(
+            option.clone(),
+            "00001.sst".to_string(),
+            Some(table_builder),
+            0,
         );
+        let table = Arc::new(table.unwrap());
+        let sstable = table.sstable().unwrap();
+        assert_eq!(sstable.indexs().filter_version, FILTER_VERSION);
+        assert!(sstable.has_bloom_filter());
+
+        // the filter answers lookups before blocks are read
+        let stats = BloomStats::default();
+        for key in &keys {
+            let lookup = format::make_lookup_key(key.as_bytes(), format::MAX_SEQUENCE);
+            assert!(table
+                .get(key.as_bytes(), &lookup, &stats)
+                .unwrap()
+                .is_some());
+            let missing = format!("{}-", key);
+            let lookup = format::make_lookup_key(missing.as_bytes(), format::MAX_SEQUENCE);
+            assert!(table
+                .get(missing.as_bytes(), &lookup, &stats)
+                .unwrap()
+                .is_none());
+        }
+        assert_eq!(stats.true_positive(), 100);
+        assert_eq!(stats.useful() + stats.false_positive(), 100);
+        assert!(stats.false_positive() < 10);
+
+        // a table written before filter versions has a filter of another hash,
+        // an empty filter stands for it, as it would reject every key if used
+        let mut index = sstable.indexs().clone();
+        index.filter_version = 0;
+        index.bloom_filter = vec![0; index.bloom_filter.len()];
+        let index = index.encode_to_vec();
+        let checksum = crate::utils::file::calculate_checksum(&index).to_le_bytes();
+        let last_block = sstable.offsets(sstable.last_offset_idx()).unwrap();
+        let mut data = sstable
+            .read(0, last_block.offset + last_block.len)
+            .unwrap()
+            .to_vec();
+        data.extend_from_slice(&index);
+        data.extend_from_slice(&(index.len() as u32).to_le_bytes());
+        data.extend_from_slice(&checksum);
+        data.extend_from_slice(&(checksum.len() as u32).to_le_bytes());
+        let path = std::path::Path::new(&option.work_dir).join("00002.sst");
+        std::fs::write(path, data).unwrap();
+
+        let table =
+            Arc::new(Table::open(option.clone(), "00002.sst".to_string(), None, 0).unwrap());
+        assert!(!table.sstable().unwrap().has_bloom_filter());
+        for key in &keys {
+            let lookup = format::make_lookup_key(key.as_bytes(), format::MAX_SEQUENCE);
+            assert!(table.filter_my_contain_key(key.as_bytes()).unwrap());
+            assert!(table
+                .get(key.as_bytes(), &lookup, &BloomStats::default())
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn test_table_restart_points() {
+        let keys = test_helper::generate_incredible_strings(1000);
+        let mut sizes = Vec::new();
+        for interval in [1, 3, 16] {
+            let mut option = test_helper::test_options(&format!("table_restart_{}", interval));
+            option.block_size = 4096;
+            option.block_restart_interval = interval;
+            let option = Arc::new(option);
+
+            let mut table_builder = TableBuilder::new(option.clone());
+            for (i, key) in keys.iter().enumerate() {
+                let ikey =
+                    format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value);
+                table_builder.add(&ikey, key.as_bytes());
+            }
+            let table = Table::open(
+                option.clone(),
+                "00001.sst".to_string(),
+                Some(table_builder),
+                0,
+            );
+            let table = Arc::new(table.unwrap());
+            assert_eq!(
+                table.sstable().unwrap().indexs().block_version,
+                BLOCK_VERSION
+            );
+            sizes.push(table.size());
+
+            // every key is found by binary search of restart points, a missing
+            // key lands on the key after it
+            let mut iter = table.new_iterator();
+            for (i, key) in keys.iter().enumerate() {
+                let lookup = format::make_lookup_key(key.as_bytes(), format::MAX_SEQUENCE);
+                let found = iter.seek(&lookup).unwrap();
+                assert_eq!(format::user_key(found), key.as_bytes());
+                assert_eq!(iter.val(), key.as_bytes());
+
+                let missing = format!("{}-", key);
+                let lookup = format::make_lookup_key(missing.as_bytes(), format::MAX_SEQUENCE);
+                match keys.get(i + 1) {
+                    Some(next) => {
+                        let found = iter.seek(&lookup).unwrap();
+                        assert_eq!(format::user_key(found), next.as_bytes());
+                    }
+                    None => assert!(iter.seek(&lookup).is_none()),
+                }
+            }
+
+            // prev scans from the restart point before the entry
+            iter.seek_to_last();
+            let mut count = keys.len();
+            while iter.valid() {
+                count -= 1;
+                assert_eq!(format::user_key(iter.key()), keys[count].as_bytes());
+                assert_eq!(iter.val(), keys[count].as_bytes());
+                iter.prev();
+            }
+            assert_eq!(count, 0);
+        }
+        // keys overlap more with the key before them than with the first key
+        assert!(sizes[2] < sizes[1] && sizes[1] < sizes[0]);
+    }
+
+    #[test]
+    fn test_block_v0() {
+        // a version 0 block, |overlap u16| |diff u16| |diff key| |val| against
+        // the base key, then the entry offsets and the checksum
+        let keys: Vec<Slice> = ["abc", "abd", "abde", "b"]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                format::make_internal_key(key.as_bytes(), i as u64 + 1, ValueType::Value)
+            })
+            .collect();
+        let base_key = &keys[0];
+        let mut data = Vec::new();
+        let mut entry_offsets = Vec::new();
+        for key in &keys {
+            let overlap = key.iter().zip(base_key).take_while(|(a, b)| a == b).count();
+            entry_offsets.push(data.len() as u32);
+            data.extend_from_slice(&(overlap as u16).to_le_bytes());
+            data.extend_from_slice(&((key.len() - overlap) as u16).to_le_bytes());
+            data.extend_from_slice(&key[overlap..]);
+            data.extend_from_slice(format::user_key(key));
+        }
+        for offset in &entry_offsets {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data.extend_from_slice(&(entry_offsets.len() as u32).to_le_bytes());
+        let checksum = crate::utils::file::calculate_checksum(&data).to_le_bytes();
+        data.extend_from_slice(&checksum);
+        data.extend_from_slice(&(checksum.len() as u32).to_le_bytes());
+
+        let block = DecodedBlock::decode_v0(&data, base_key).unwrap();
+        let mut bi = BlockIterator::new(Arc::new(block));
+        bi.seek_to_first();
+        for key in &keys[1..] {
+            bi.next().unwrap();
+            assert_eq!(bi.key(), key);
+            assert_eq!(bi.val(), format::user_key(key));
+        }
+        assert!(bi.next().is_none());
+        for key in keys[..3].iter().rev() {
+            bi.prev().unwrap();
+            assert_eq!(bi.key(), key);
+        }
+        assert!(bi.prev().is_none());
+        let lookup = format::make_lookup_key(b"abda", format::MAX_SEQUENCE);
+        assert_eq!(bi.seek(&lookup), Some(&keys[2]));
+    }
+
+    #[test]
+    fn test_table_builder_empty() {
+        let option = Arc::new(test_helper::test_options("table_builder_empty"));
+        let mut table_builder = TableBuilder::new(option.clone());
+        let res = table_builder.flush("001".to_string());
+        assert!(matches!(res, Err(crate::error::Error::InvalidArgument(_))));
+        let path = std::path::Path::new(&option.work_dir).join("001");
+        assert!(!path.exists());
     }
 }
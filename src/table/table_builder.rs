@@ -1,20 +1,33 @@
-use crate::db::options::Options;
+use crate::db::format;
+use crate::db::options::{Compression, Options};
+use crate::error::{Error, Result};
 use crate::utils::slice::Slice;
-use crate::pb::pb::{BlockOffset, TableIndex};
+use crate::pb::pb::{BlockOffset, CompressionType, TableIndex};
 use crate::file::sstable::SSTable;
 use crate::file;
-use crate::utils::filter::Filter;
+use crate::utils::filter::{Filter, FILTER_VERSION};
+use crate::utils::compress;
+use crate::utils::encodings::{decode_varint_u32, encode_varint_u32};
 use prost::Message;
 use std::sync::Arc;
+
+// BLOCK_VERSION is recorded in the index of a table, entries of version 0 blocks
+// overlap with the base key of the block and the block ends with the offset of
+// every entry, they are converted when the block is decoded
+pub const BLOCK_VERSION: u32 = 1;
+
 pub struct TableBuilder {
     opt: Arc<Options>,
-    sst_size: u64,
     cur_block: Block,
     blocks: Vec<Block>,
     key_count: u32,
     key_hashs: Vec<u32>,
-    base_key: Slice,
+    // hashs of distinct key prefixes, keys of a prefix are added in a row
+    prefix_hashs: Vec<u32>,
+    last_prefix: Option<Slice>,
     estimate_size: i64,
+    max_seq: u64,
+    compression: Compression,
 }
 #[derive(Default)]
 struct BuildData{
@@ -25,33 +38,40 @@ struct BuildData{
 }
 #[derive(Clone)]
 pub struct Block {
-    offset: u32,
-    entries_index_start: u32,
     data: Slice,
     base_key: Slice,
-    entry_offsets: Vec<u32>,
+    // the key of the last entry, the next entry overlaps with it
+    last_key: Slice,
+    // offsets of the entries that hold a full key, every restart interval entries
+    restarts: Vec<u32>,
+    num_entries: u32,
     end: u32,
     estimate_sz: i64,
+    compression: CompressionType,
 }
 
+// an entry is |overlap| |diff| |val_len| |diff key| |val|, lens use varint encode,
+// the key overlaps with the key before it, an entry at a restart point holds its full key
 #[derive(Default)]
 struct Header {
-    overlap: u16,
-    diff: u16,
+    overlap: u32,
+    diff: u32,
+    val_len: u32,
 }
 
 impl Header {
-    pub fn decode(header: &[u8]) -> Self {
-        assert!(header.len() == 4);
-        let overlap = u16::from_le_bytes([header[0], header[1]]);
-        let diff = u16::from_le_bytes([header[2], header[3]]);
-        Header { overlap, diff }
+    // decode the header at the start of entry, return it and its len
+    pub fn decode(entry: &[u8]) -> Option<(Self, usize)> {
+        let (overlap, n1) = decode_varint_u32(entry)?;
+        let (diff, n2) = decode_varint_u32(&entry[n1..])?;
+        let (val_len, n3) = decode_varint_u32(&entry[n1 + n2..])?;
+        Some((Header { overlap, diff, val_len }, n1 + n2 + n3))
     }
 
     pub fn encode(&self) -> Vec<u8> {
-        let mut header = Vec::with_capacity(4);
-        header.extend_from_slice(&self.overlap.to_le_bytes());
-        header.extend_from_slice(&self.diff.to_le_bytes());
+        let mut header = encode_varint_u32(self.overlap);
+        header.append(&mut encode_varint_u32(self.diff));
+        header.append(&mut encode_varint_u32(self.val_len));
 
         header
     }
@@ -59,34 +79,45 @@ impl Header {
 
 impl Block {
     pub fn new(opt : Arc<Options>)->Self{
-        let mut v = Vec::new();
-        v.reserve(opt.block_size as usize);
+        let v = Vec::with_capacity(opt.block_size as usize);
         Block{
-            offset: 0,
-            entries_index_start: 0,
             data: v,
             base_key: Slice::new(),
-            entry_offsets: Vec::new(),
+            last_key: Slice::new(),
+            restarts: Vec::new(),
+            num_entries: 0,
             end: 0,
             estimate_sz: 0,
+            compression: CompressionType::None,
         }
     }
-    pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
-    }
 }
 
 impl TableBuilder {
+    #[cfg(test)]
     pub fn new(opt : Arc<Options>)->Self{
+        let compression = opt.compression;
+        Self::with_compression(opt, compression)
+    }
+
+    // builder of a table in the level, blocks are compressed as the level asks
+    pub fn with_level(opt : Arc<Options>, level : u32)->Self{
+        let compression = opt.compression_of(level);
+        Self::with_compression(opt, compression)
+    }
+
+    fn with_compression(opt : Arc<Options>, compression : Compression)->Self{
         TableBuilder{
             opt : opt.clone(),
-            sst_size : opt.sstable_maxsz,
             cur_block: Block::new(opt.clone()),
             blocks: Vec::new(),
             key_count: 0,
             key_hashs: Vec::new(),
-            base_key: Slice::new(),
+            prefix_hashs: Vec::new(),
+            last_prefix: None,
             estimate_size: 0,
+            max_seq: 0,
+            compression,
         }
     }
     pub fn add(&mut self, key: &[u8], value: &[u8]){
@@ -94,44 +125,58 @@ impl TableBuilder {
             self.finish_block();
             self.cur_block = Block::new(self.opt.clone())
         }
-        self.key_hashs.push(Filter::hash(&key));
-
-        let mut diffkey;
+        // key is an internal key, filter is searched by user key
+        self.key_hashs.push(Filter::hash(format::user_key(key)));
+        if let Some(extractor) = &self.opt.prefix_extractor{
+            if let Some(prefix) = extractor.prefix(format::user_key(key)){
+                if self.last_prefix.as_deref() != Some(prefix){
+                    self.prefix_hashs.push(Filter::hash(prefix));
+                    self.last_prefix = Some(prefix.to_vec());
+                }
+            }
+        }
+        self.max_seq = std::cmp::max(self.max_seq, format::seq(key));
 
-        if self.cur_block.base_key.len() == 0 {
+        if self.cur_block.base_key.is_empty() {
             self.cur_block.base_key = Slice::from(key);
-            diffkey = Slice::from(key);
         }
-        else {
-            diffkey = self.key_diff(key);
+        let overlap = if self.cur_block.num_entries.is_multiple_of(self.opt.block_restart_interval) {
+            self.cur_block.restarts.push(self.cur_block.end);
+            0
         }
+        else {
+            self.key_overlap(key)
+        };
         let header = Header{
-            overlap : (key.len() - diffkey.len()) as u16,
-            diff : diffkey.len() as u16,
+            overlap : overlap as u32,
+            diff : (key.len() - overlap) as u32,
+            val_len : value.len() as u32,
         };
-        self.cur_block.entry_offsets.push(self.cur_block.end);
         self.append(&mut header.encode());
-        self.append(&mut diffkey);
+        self.append(&mut Slice::from(&key[overlap..]));
         self.append(&mut Slice::from(value));
+        self.cur_block.last_key = Slice::from(key);
+        self.cur_block.num_entries += 1;
     }
 
     pub fn is_empty(&self)->bool{
         self.key_hashs.is_empty() 
     }
     fn try_finish_block(&mut self, key: &[u8], value: &[u8]) -> bool {
-        assert!((self.cur_block.entry_offsets.len() as u32 + 1) * 4 + 4 + 8 + 4 < u32::MAX);
-        let entries_offsets_size = (self.cur_block.entry_offsets.len() + 1) * 4 
+        assert!((self.cur_block.restarts.len() as u32 + 1) * 4 + 4 + 8 + 4 < u32::MAX);
+        let restarts_size = (self.cur_block.restarts.len() + 1) * 4 
         + 4 // size of blocks
         + 8 // sum64 in checksum proto
         + 4 // checksum length
         ;
         self.cur_block.estimate_sz = self.cur_block.end as i64
-            + 4 
+            + 15 // max len of the header
             + key.len() as i64
             + value.len() as i64
-            + entries_offsets_size as i64;
+            + restarts_size as i64;
 
-         self.cur_block.estimate_sz > self.opt.block_size as i64
+         // an empty block always takes the entry, even if it is larger than a block
+         self.cur_block.estimate_sz > self.opt.block_size as i64 && self.cur_block.num_entries > 0
     }
     fn append(&mut self, data : &mut Vec<u8>){
         self.allocate(data.len() as i32);
@@ -142,27 +187,26 @@ impl TableBuilder {
         self.cur_block.data.append(data);
     }
     fn allocate(&mut self, need : i32){
-        let mut b = &mut self.cur_block;
+        let b = &mut self.cur_block;
         if (b.data.len() as i32) - (b.end as i32) < need{
             let mut sz = 2*b.data.len() as i32;
-            if b.end as i32 + need > sz as i32{
+            if b.end as i32 + need > sz{
                 sz = b.end as i32 + need;
             }
 
-            let mut tmp = Vec::new();
-            tmp.reserve(sz as usize);
+            let mut tmp = Vec::with_capacity(sz as usize);
             tmp.append(&mut b.data);
             b.data = tmp;
         }
     }
     fn finish_block(&mut self){
         let mut v = Vec::new();
-        // put entry_offsets
-        for &num in &self.cur_block.entry_offsets{
+        // put restarts
+        for &num in &self.cur_block.restarts{
             v.extend_from_slice(&num.to_le_bytes());
         }
-        // put entry_offsets_len
-        v.extend_from_slice(&(self.cur_block.entry_offsets.len() as u32).to_le_bytes());
+        // put restarts_len
+        v.extend_from_slice(&(self.cur_block.restarts.len() as u32).to_le_bytes());
         self.append(&mut v);
         v.clear();
         let cs = crate::utils::file::calculate_checksum(&self.cur_block.data[..self.cur_block.end as usize]).to_le_bytes();
@@ -173,25 +217,38 @@ impl TableBuilder {
 
         self.append(&mut v);
 
+        // the checksum is compressed with the block, it is verified after the block is decompressed
+        let end = self.cur_block.end as usize;
+        if let Some((kind, data)) = compress::compress(self.compression, &self.cur_block.data[..end]){
+            if data.len() < end - end / 8 {
+                self.cur_block.end = data.len() as u32;
+                self.cur_block.estimate_sz = data.len() as i64;
+                self.cur_block.data = data;
+                self.cur_block.compression = kind;
+            }
+        }
+
         self.estimate_size += self.cur_block.estimate_sz;
-        self.key_count += self.cur_block.entry_offsets.len() as u32;
+        self.key_count += self.cur_block.num_entries;
         self.blocks.push(self.cur_block.clone());
     }
     
-    fn key_diff(&self,key: &[u8])->Slice{
+    // the len of the prefix the key shares with the last key of the block
+    fn key_overlap(&self,key: &[u8])->usize{
+        let last_key = &self.cur_block.last_key;
         let mut i = 0;
-        while i < key.len() && i < self.cur_block.base_key.len() {
-            if key[i] != self.cur_block.base_key[i]{
+        while i < key.len() && i < last_key.len() {
+            if key[i] != last_key[i]{
                 break;
             }
             i+=1;
         }
-        Vec::from(&key[i..])
+        i
     }
 
-    pub fn flush(&mut self,name : String) ->std::io::Result<SSTable>{
+    pub fn flush(&mut self,name : String) ->Result<SSTable>{
         
-        let build_data = self.done();
+        let build_data = self.done()?;
         let options = file::file::Options{
             size : build_data.size as u64,
             file_name : name,
@@ -200,6 +257,8 @@ impl TableBuilder {
         };
         let mut ss = SSTable::open(options)?;
         ss.write_table(&build_data.copy()); 
+        // the table is on disk before the manifest records it
+        ss.sync()?;
         Ok(ss)
     }
 
@@ -208,21 +267,22 @@ impl TableBuilder {
     }
 
     // note: can't move a part under &mut 
-    fn done(&mut self) ->BuildData{
-        self.finish_block();
-        if self.blocks.len() == 0{
-            return BuildData::default();
+    // a table without entries has no block to index, so it is refused
+    fn done(&mut self) ->Result<BuildData>{
+        if self.is_empty(){
+            return Err(Error::InvalidArgument("a table needs at least one entry".to_string()));
         }
-        let mut f;
-        let mut bd = BuildData::default();
-        bd.blocks = self.blocks.clone();
-        if self.opt.bloom_false_positive > 0.0{
-
+        self.finish_block();
+        let mut bd = BuildData{
+            blocks : self.blocks.clone(),
+            ..Default::default()
+        };
+        let f = if self.opt.bloom_false_positive > 0.0{
             let bits = Filter::bloom_bits_per_key(self.key_hashs.len() as i32, self.opt.bloom_false_positive);
-            f = Filter::with_keys(&self.key_hashs, bits).get();
+            Filter::with_keys(&self.key_hashs, bits).get()
         }
-        else {f = Vec::new();}
-        let (index, data_size) = self.build_index(f);
+        else {Vec::new()};
+        let (index, data_size) = self.build_index(f, self.prefix_bloom());
         let checksum = crate::utils::file::calculate_checksum(&index).to_le_bytes().to_vec();
 
         bd.index = index;
@@ -232,25 +292,43 @@ impl TableBuilder {
         
         // debug!
         //println!("bd.size : {}, data size: {}, index size {}",bd.size, data_size, bd.index.len());
-        bd
+        Ok(bd)
 
     }
-    fn build_index(&mut self, bloom : Vec<u8>)->(Vec<u8>, u32){
+    // a table without keys of a prefix has an empty filter, which rejects
+    // every prefix, so a filter is built whenever there is an extractor
+    fn prefix_bloom(&self) ->Option<(String, Vec<u8>)>{
+        let extractor = self.opt.prefix_extractor.as_ref()?;
+        if self.opt.bloom_false_positive <= 0.0{
+            return None;
+        }
+        let bits = Filter::bloom_bits_per_key(self.prefix_hashs.len() as i32, self.opt.bloom_false_positive);
+        Some((extractor.name(), Filter::with_keys(&self.prefix_hashs, bits).get()))
+    }
+
+    fn build_index(&mut self, bloom : Vec<u8>, prefix_bloom : Option<(String, Vec<u8>)>)->(Vec<u8>, u32){
         let mut table_index = TableIndex::default();
-        if bloom.len() > 0{
-            table_index.bloom_filter = bloom.clone();
+        if !bloom.is_empty(){
+            table_index.bloom_filter = bloom;
+        }
+        if let Some((extractor, prefix_bloom)) = prefix_bloom{
+            table_index.prefix_extractor = extractor;
+            table_index.prefix_bloom_filter = prefix_bloom;
         }
+        table_index.filter_version = FILTER_VERSION;
+        table_index.block_version = BLOCK_VERSION;
         table_index.key_count = self.key_count;
-        table_index.offsets = self.write_block_offsets(&mut table_index);
+        table_index.max_seq = self.max_seq;
+        table_index.offsets = self.write_block_offsets();
         let mut data_size = 0;
         for x in &self.blocks{
             data_size += x.end;
         } 
-        let mut data = table_index.encode_to_vec();
+        let data = table_index.encode_to_vec();
         (data, data_size)
     }
 
-    fn write_block_offsets(&self,table_index : &mut TableIndex)->Vec<BlockOffset>{
+    fn write_block_offsets(&self)->Vec<BlockOffset>{
         let mut v = Vec::new();
         let mut start_offset = 0;
         for block in &self.blocks{
@@ -264,6 +342,7 @@ impl TableBuilder {
             key : bl.base_key.clone(),
             len : bl.end,
             offset : start_offset,
+            compression : bl.compression as i32,
         }
     }
 
@@ -277,9 +356,7 @@ impl BuildData{
         for block in &self.blocks{
             v.extend_from_slice(&block.data[..block.end as usize]);
         }
-        let data_size = v.len();
         v.extend_from_slice(&self.index);
-        let index_size = v.len() - data_size;
         v.extend_from_slice(&(self.index.len() as u32).to_le_bytes());
         //debug
         //println!("{}", v.len());
@@ -294,142 +371,214 @@ impl BuildData{
     }
 }
 
+// a decoded block holds the entries of a block and their restart points, it is
+// shared by block iterators and the block cache
+#[derive(Default)]
+pub struct DecodedBlock{
+    data : Slice,
+    restarts : Vec<u32>,
+}
+
+// block iterator holds the decoded block, so it can outlive the borrow of the table
 #[derive(Default)]
-pub struct BlockIterator<'a>{
-    data : &'a [u8],
-    idx : i32,
-    base_key : Slice,
+pub struct BlockIterator{
+    block : Arc<DecodedBlock>,
+    // offset of the current entry and of the entry after it
+    offset : usize,
+    next_offset : usize,
+    // the last restart point at or before the current entry
+    restart : usize,
     key : Slice,
     val: Slice,
-    entry_offsets : Vec<u32>,
-    
 }
 
-impl <'a>BlockIterator<'a> {
-    pub fn new(data : &'a [u8], base_key : &[u8]) ->Self{
+impl BlockIterator {
+    pub fn new(block : Arc<DecodedBlock>) ->Self{
         BlockIterator{
-            data,
-            idx : 0,
-            base_key : Slice::from(base_key),
+            block,
+            offset : 0,
+            next_offset : 0,
+            restart : 0,
             key : Slice::new(),
             val : Slice::new(), 
-            entry_offsets : Vec::new(),
         } 
     } 
     pub fn seek_to_first(&mut self){
-        self.set_idx(0);
+        self.seek_to_restart(0);
     }
     
     pub fn seek_to_last(&mut self){
-        self.set_idx(self.entry_offsets.len() as i32 - 1);
+        if self.seek_to_restart(self.block.restarts.len().saturating_sub(1)).is_none(){
+            return;
+        }
+        while self.next_offset < self.block.data.len() && self.parse_next().is_some(){}
     }
 
     pub fn next(&mut self)->Option<()>{
-        let idx = self.idx +1;
-        if idx < 0 || idx >= self.entry_offsets.len() as i32{
+        if self.next_offset >= self.block.data.len(){
+            return None;
+        }
+        self.parse_next()
+    }
+
+    // prev scans from the restart point before the current entry, as entries
+    // can only be decoded forward
+    pub fn prev(&mut self)->Option<()>{
+        let offset = self.offset;
+        if offset == 0{
             return None;
         }
-        self.set_idx(self.idx+1);
+        let mut restart = self.restart;
+        if self.block.restarts[restart] as usize == offset{
+            restart -= 1;
+        }
+        self.seek_to_restart(restart)?;
+        while self.next_offset < offset{
+            self.parse_next()?;
+        }
         Some(())
     }
-    // todo! to ref but not to clone entry_offsets
+    // seek to the first entry whose internal key is greater or equal than key,
+    // return None if all entries in the block are less than key
     pub fn seek(&mut self, key : &[u8])->Option<&Slice>{
-        let key_len = self.entry_offsets.len();
-        let mut seek_arr = Vec::new();
-        for i in 0..key_len{
-            seek_arr.push(i);
+        // binary search the last restart point whose key is less than key,
+        // the entry is after it
+        let (mut lo, mut hi) = (0, self.block.restarts.len().checked_sub(1)?);
+        while lo < hi{
+            let mid = lo + (hi - lo).div_ceil(2);
+            if format::compare_internal_key(self.restart_key(mid)?, key).is_lt(){
+                lo = mid;
+            }
+            else{
+                hi = mid - 1;
+            }
         }
-        let found_idx = seek_arr.binary_search_by(|&i|{
-            //println!("i is {}", i);
-            self.set_idx(i as i32);
-            self.key.cmp(&Vec::from(key))
-        });
-
 
-        if let Ok(i) = found_idx{
-            self.set_idx(i as i32);
-            Some(&self.key)
-        } 
-        else{
-            None
+        self.seek_to_restart(lo)?;
+        while format::compare_internal_key(&self.key, key).is_lt(){
+            self.next()?;
         }
-
+        Some(&self.key)
     } 
 
-    pub fn valid(&self, idx : i32) ->bool{
-        idx >= 0 && idx < self.entry_offsets.len() as i32
-    }
     pub fn key(&self) ->&Slice{
         &self.key
     }
     pub fn val(&self) ->&Slice{
         &self.val
     }
-    pub fn init(&mut self)->Result<(), String>{
-        let data = self.data;
+
+    // the full key of the entry at the restart point
+    fn restart_key(&self, restart : usize)->Option<&[u8]>{
+        let entry = self.block.data.get(self.block.restarts[restart] as usize..)?;
+        let (header, header_len) = Header::decode(entry)?;
+        entry.get(header_len..header_len + header.diff as usize)
+    }
+
+    fn seek_to_restart(&mut self, restart : usize)->Option<()>{
+        let offset = *self.block.restarts.get(restart)? as usize;
+        self.restart = restart;
+        self.next_offset = offset;
+        self.key.clear();
+        self.parse_next()
+    }
+
+    // decode the entry at next_offset, its key overlaps with the current key
+    fn parse_next(&mut self)->Option<()>{
+        let block = &self.block;
+        let entry = block.data.get(self.next_offset..)?;
+        let (header, header_len) = Header::decode(entry)?;
+        let key_end = header_len + header.diff as usize;
+        let val_end = key_end + header.val_len as usize;
+        if header.overlap as usize > self.key.len() || val_end > entry.len(){
+            return None;
+        }
+        self.key.truncate(header.overlap as usize);
+        self.key.extend_from_slice(&entry[header_len..key_end]);
+        self.val = Slice::from(&entry[key_end..val_end]);
+        self.offset = self.next_offset;
+        self.next_offset += val_end;
+        while self.restart + 1 < block.restarts.len() && block.restarts[self.restart + 1] as usize <= self.offset{
+            self.restart += 1;
+        }
+        Some(())
+    }
+}
+
+impl DecodedBlock {
+    // decode reads the restart points at the end of the block, return None if
+    // the block is broken
+    pub fn decode(data : &[u8])->Option<DecodedBlock>{
+        let (data, restarts) = Self::decode_trailer(data)?;
+        // the first entry is a restart point, and restart points are in order
+        if restarts.first() != Some(&0) || restarts.windows(2).any(|w| w[0] >= w[1]) || *restarts.last()? as usize >= data.len(){
+            return None;
+        }
+        Some(DecodedBlock{
+            data : Slice::from(data),
+            restarts,
+        })
+    }
+
+    // decode a version 0 block, whose keys overlap with the base key and whose
+    // entries end at the offset of the next entry, it is converted to a block
+    // with a restart point at every entry
+    pub fn decode_v0(data : &[u8], base_key : &[u8])->Option<DecodedBlock>{
+        let (data, entry_offsets) = Self::decode_trailer(data)?;
+        let mut block = DecodedBlock::default();
+        for (i, &start) in entry_offsets.iter().enumerate(){
+            let end = entry_offsets.get(i + 1).map_or(data.len(), |&end| end as usize);
+            let entry = data.get(start as usize..end)?;
+            let overlap = u16::from_le_bytes(entry.get(0..2)?.try_into().unwrap()) as usize;
+            let diff = u16::from_le_bytes(entry.get(2..4)?.try_into().unwrap()) as usize;
+            let mut key = Slice::from(base_key.get(..overlap)?);
+            key.extend_from_slice(entry.get(4..4 + diff)?);
+            let val = &entry[4 + diff..];
+
+            block.restarts.push(block.data.len() as u32);
+            let header = Header{ overlap : 0, diff : key.len() as u32, val_len : val.len() as u32 };
+            block.data.append(&mut header.encode());
+            block.data.append(&mut key);
+            block.data.extend_from_slice(val);
+        }
+        Some(block)
+    }
+
+    // the trailer is |u32 offsets| |offsets len| |checksum| |checksum len|,
+    // return the entries before it and the offsets
+    fn decode_trailer(data : &[u8])->Option<(&[u8], Vec<u32>)>{
         let mut read_pos = data.len();
 
-        //println!("data len : {}",data.len());
-        
         // read checksum_len
-        let checksum_len = u32::from_le_bytes(data[read_pos-4..read_pos].try_into().unwrap());
+        let checksum_len = u32::from_le_bytes(data.get(read_pos.checked_sub(4)?..read_pos)?.try_into().unwrap());
         read_pos -=4;
 
-        //println!("checksum len:{}",checksum_len);
-
         // read checksum
-        let checksum = &data[read_pos-checksum_len as usize..read_pos as usize];
+        let checksum = data.get(read_pos.checked_sub(checksum_len as usize)?..read_pos)?;
         read_pos -=checksum_len as usize;
 
-        if crate::utils::file::verify_checksum(&data[..read_pos], checksum) == false{
-            return Err("verify checksum failed".to_string());
+        if !crate::utils::file::verify_checksum(&data[..read_pos], checksum){
+            return None;
         }
 
-        // read len of entry_offsets
-        let num_entries = u32::from_le_bytes(data[read_pos-4..read_pos].try_into().unwrap());
+        // read len of offsets
+        let num_offsets = u32::from_le_bytes(data.get(read_pos.checked_sub(4)?..read_pos)?.try_into().unwrap());
         read_pos -=4;
 
-
-        //debug
-        //println!("num entries : {}", num_entries);
-        // read entry_offsets
-        let entry_offsets = &data[read_pos-num_entries as usize*4.. read_pos];
-        for i in 0..num_entries as usize{
-            let offset = u32::from_le_bytes(entry_offsets[i*4 .. i*4 + 4].try_into().unwrap());
-            self.entry_offsets.push(offset);
+        // read offsets
+        let offsets_data = data.get(read_pos.checked_sub(num_offsets as usize*4)?.. read_pos)?;
+        let mut offsets = Vec::with_capacity(num_offsets as usize);
+        for i in 0..num_offsets as usize{
+            let offset = u32::from_le_bytes(offsets_data[i*4 .. i*4 + 4].try_into().unwrap());
+            offsets.push(offset);
         }
-        read_pos -= num_entries as usize*4;
-
-        self.data = &data[0..read_pos];
+        read_pos -= num_offsets as usize*4;
 
-        Ok(())
-        
+        Some((&data[..read_pos], offsets))
     }
-    fn set_idx(&mut self,i : i32){
 
-        //debug
-        //println!("now idx in block iter, {}", i);
-        self.idx = i;
-        assert!(i>=0 && i < self.entry_offsets.len() as i32);
-        let start_offset = self.entry_offsets[i as usize];
-        let end_offset;
-        if i as usize + 1 == self.entry_offsets.len(){
-            end_offset = self.data.len() as u32;
-        } 
-        else {
-            end_offset = self.entry_offsets[i as usize +1];
-        }
-        let entry = &self.data[start_offset as usize..end_offset as usize];
-
-        //debug
-        //println!("entry : {:?}",entry);
-        let header = Header::decode(&entry[0..4]);
-        let mut key = Slice::new();
-        key.extend_from_slice(&self.base_key[0..header.overlap as usize]); 
-        key.extend_from_slice(&entry[4..4+header.diff as usize]);
-        self.key = key;
-        self.val = Slice::from(&entry[4+header.diff as usize..]);
+    // the memory the block takes
+    pub fn size(&self) ->usize{
+        self.data.len() + self.restarts.len() * 4
     }
-    
 }
-
@@ -0,0 +1,191 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// LruCache is a cache bounded by the total charge of its entries, it is split
+// into shards by the hash of keys, every shard has its own lock and evicts its
+// least recently used entries once its charge is over its part of capacity.
+// vals are handed out as Arc, so an evicted val lives on with its users
+pub struct LruCache<K, V> {
+    shards: Vec<Mutex<LruShard<K, V>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    last_id: AtomicU64,
+}
+
+struct LruShard<K, V> {
+    capacity: usize,
+    usage: usize,
+    // key -> (val, charge, tick of the last use)
+    entries: HashMap<K, (Arc<V>, usize, u64)>,
+    // tick of the last use -> key, the first is the least recently used
+    lru: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize, num_shards: usize) -> Self {
+        let num_shards = std::cmp::max(num_shards, 1);
+        let shards = (0..num_shards)
+            .map(|_| {
+                Mutex::new(LruShard {
+                    capacity: capacity / num_shards,
+                    usage: 0,
+                    entries: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    tick: 0,
+                })
+            })
+            .collect();
+        LruCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    // new_id returns an id that no other user of the cache gets, users that
+    // put it in their keys never collide, like NewId of the leveldb cache
+    pub fn new_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // get returns the val of key and makes it the most recently used
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let val = self.shard(key).lock().unwrap().get(key);
+        let counter = if val.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        val
+    }
+
+    // insert adds the val of key that takes charge of capacity, a val larger
+    // than a shard is not cached
+    pub fn insert(&self, key: K, val: Arc<V>, charge: usize) {
+        self.shard(&key).lock().unwrap().insert(key, val, charge);
+    }
+
+    pub fn remove(&self, key: &K) -> Option<Arc<V>> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    // the number of gets that found their key
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    // the number of gets that did not find their key
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    // the total charge of the cached vals
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().usage).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<LruShard<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> LruShard<K, V> {
+    fn get(&mut self, key: &K) -> Option<Arc<V>> {
+        self.tick += 1;
+        let (val, _, tick) = self.entries.get_mut(key)?;
+        let old = std::mem::replace(tick, self.tick);
+        let val = val.clone();
+        self.lru.remove(&old);
+        self.lru.insert(self.tick, key.clone());
+        Some(val)
+    }
+
+    fn insert(&mut self, key: K, val: Arc<V>, charge: usize) {
+        self.remove(&key);
+        if charge > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        self.usage += charge;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(key, (val, charge, self.tick));
+
+        while self.usage > self.capacity {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some((_, charge, _)) = self.entries.remove(&key) {
+                self.usage -= charge;
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Arc<V>> {
+        let (val, charge, tick) = self.entries.remove(key)?;
+        self.usage -= charge;
+        self.lru.remove(&tick);
+        Some(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_cache() {
+        // a single shard makes the order of eviction certain
+        let cache = LruCache::new(10, 1);
+        for i in 0..5 {
+            cache.insert(i, Arc::new(i * 10), 2);
+        }
+        assert_eq!(cache.usage(), 10);
+        assert_eq!(cache.get(&0), Some(Arc::new(0)));
+
+        // 1 is the least recently used since 0 was got
+        cache.insert(5, Arc::new(50), 2);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&0), Some(Arc::new(0)));
+        assert_eq!(cache.len(), 5);
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+        // a larger val evicts as many as it needs
+        cache.insert(6, Arc::new(60), 5);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&3), None);
+        assert_eq!(cache.get(&4), None);
+        assert_eq!(cache.usage(), 9);
+
+        // a val larger than the cache is not cached
+        cache.insert(7, Arc::new(70), 11);
+        assert_eq!(cache.get(&7), None);
+        assert_eq!(cache.remove(&6), Some(Arc::new(60)));
+        assert_eq!(cache.usage(), 4);
+
+        let cache = LruCache::new(1000, 8);
+        for i in 0..1000 {
+            cache.insert(i, Arc::new(i), 1);
+        }
+        assert!(cache.usage() <= 1000 && cache.len() > 500);
+        assert_ne!(cache.new_id(), cache.new_id());
+    }
+}
@@ -0,0 +1,53 @@
+use crate::db::options::Compression;
+use crate::pb::pb::CompressionType;
+
+// compress returns the type and the data compressed by compression, or None if
+// compression is None or compressing fails
+pub fn compress(compression: Compression, data: &[u8]) -> Option<(CompressionType, Vec<u8>)> {
+    match compression {
+        Compression::None => None,
+        Compression::Snappy => {
+            let data = snap::raw::Encoder::new().compress_vec(data).ok()?;
+            Some((CompressionType::Snappy, data))
+        }
+        Compression::Zstd(level) => {
+            let data = zstd::bulk::compress(data, level).ok()?;
+            Some((CompressionType::Zstd, data))
+        }
+    }
+}
+
+// decompress returns the data that was compressed as kind, or None if the
+// data is broken
+pub fn decompress(kind: CompressionType, data: &[u8]) -> Option<Vec<u8>> {
+    match kind {
+        CompressionType::None => Some(data.to_vec()),
+        CompressionType::Snappy => snap::raw::Decoder::new().decompress_vec(data).ok(),
+        CompressionType::Zstd => {
+            // the decompressed size is in the frame header
+            let len = zstd::zstd_safe::get_frame_content_size(data).ok()??;
+            zstd::bulk::decompress(data, usize::try_from(len).ok()?).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let data = "{\"key\": \"val\"}".repeat(100).into_bytes();
+        assert!(compress(Compression::None, &data).is_none());
+
+        for compression in [Compression::Snappy, Compression::Zstd(3)] {
+            let (kind, compressed) = compress(compression, &data).unwrap();
+            assert!(compressed.len() < data.len() / 4);
+            assert_eq!(decompress(kind, &compressed), Some(data.clone()));
+
+            // broken data is not decompressed
+            assert_eq!(decompress(kind, &compressed[..compressed.len() / 2]), None);
+        }
+        assert_eq!(decompress(CompressionType::None, &data), Some(data));
+    }
+}
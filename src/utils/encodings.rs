@@ -2,7 +2,9 @@
 // directly encode to src
 
 // if not return ptr, consider using **ptr
-pub fn encode_varint_u32_ptr(mut ptr: *mut u8, mut value: u32) -> *mut u8 {
+/// # Safety
+/// ptr must be valid for writes of 5 bytes
+pub unsafe fn encode_varint_u32_ptr(mut ptr: *mut u8, mut value: u32) -> *mut u8 {
     let b = 128;
     unsafe {
         while value >= b {
@@ -16,7 +18,9 @@ pub fn encode_varint_u32_ptr(mut ptr: *mut u8, mut value: u32) -> *mut u8 {
     ptr
 }
 
-pub fn encode_varint_u64_ptr(mut ptr: *mut u8, mut value: u64) -> *mut u8 {
+/// # Safety
+/// ptr must be valid for writes of 10 bytes
+pub unsafe fn encode_varint_u64_ptr(mut ptr: *mut u8, mut value: u64) -> *mut u8 {
     //if first bit is zero, terminal
     let b = 128;
     unsafe {
@@ -48,7 +52,7 @@ pub fn varint_length(value: u32) -> u32 {
     let mut len: u32 = 1;
     let mut value = value;
     while value >= 128 {
-        value = value >> 7;
+        value >>= 7;
         len += 1;
     }
     len
@@ -86,7 +90,9 @@ pub fn decode_varint_u64(src: &[u8]) -> Option<(u64, usize)> {
     None
 }
 
-pub fn encode_slice(mut ptr: *mut u8, s: &Vec<u8>) -> *mut u8 {
+/// # Safety
+/// ptr must be valid for writes of s.len() bytes
+pub unsafe fn encode_slice(mut ptr: *mut u8, s: &[u8]) -> *mut u8 {
     unsafe {
         for &x in s {
             *ptr = x;
@@ -96,6 +102,7 @@ pub fn encode_slice(mut ptr: *mut u8, s: &Vec<u8>) -> *mut u8 {
     ptr
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -107,24 +114,22 @@ mod tests {
 
         let key = "hello";
         let value = " world";
-        let mut ptr = v.as_mut_ptr();
-        let data = ptr;
-        let ptr = encode_varint_u32_ptr(ptr, key.len() as u32);
-        let ptr = encode_varint_u32_ptr(ptr, value.len() as u32);
+        let ptr = v.as_mut_ptr();
+        let ptr = unsafe { encode_varint_u32_ptr(ptr, key.len() as u32) };
+        let ptr = unsafe { encode_varint_u32_ptr(ptr, value.len() as u32) };
 
         let x = decode_varint_u32(&v).unwrap();
         assert_eq!(x.0, key.len() as u32);
         let y = decode_varint_u32(&v[x.1..]).unwrap();
         assert_eq!(y.0, value.len() as u32);
 
-        let ptr = encode_slice(ptr, &key.as_bytes().to_vec());
-        let ptr = encode_slice(ptr, &value.as_bytes().to_vec());
+        let ptr = unsafe { encode_slice(ptr, key.as_bytes()) };
+        unsafe { encode_slice(ptr, value.as_bytes()) };
 
         let key_decoded = &v[x.1 + y.1..][..x.0 as usize];
         let val_decoded = &v[x.1 + y.1 + x.0 as usize..][..y.0 as usize];
 
         assert_eq!(key_decoded, key.as_bytes());
         assert_eq!(val_decoded, value.as_bytes());
-
     }
 }
@@ -6,7 +6,7 @@ pub fn calculate_checksum(data: &[u8]) -> u64 {
 }
 pub fn calculate_checksum32(data: &[u8]) -> u32 {
     let crc32 = Crc::<u32>::new(&CRC_32_ISO_HDLC);
-    crc32.checksum(data) as u32
+    crc32.checksum(data)
 }
 
 pub fn verify_checksum(data: &[u8], checksum: &[u8]) -> bool {
@@ -16,9 +16,9 @@ pub fn verify_checksum_32(data: &[u8], checksum: &[u8]) -> bool {
     calculate_checksum32(data).to_le_bytes() == checksum
 }
 
-
 pub mod file_helper {
 
+    use crate::error::{Error, Result};
     use std::collections::HashSet;
 
     // use id to get wal file name
@@ -50,32 +50,25 @@ pub mod file_helper {
             .to_string()
     }
     // use sst file name to get its fid
-    pub fn fid(name: &str) -> Result<u64, String> {
-        if !name.ends_with(".sst") {
-            return Err("not a sst  file".to_string());
-        }
-
+    pub fn fid(name: &str) -> Result<u64> {
         // remove the ".sst" suffix
-        let name = name.trim_end_matches(".sst");
-
-        match name.parse::<u64>() {
-            Ok(id) => Ok(id),
-            Err(e) => Err(e.to_string()),
-        }
+        let id = name
+            .strip_suffix(".sst")
+            .and_then(|id| id.parse::<u64>().ok());
+        id.ok_or_else(|| Error::InvalidArgument(format!("{} is not a sst file", name)))
     }
 
     // load all sst file id in a dir
-    pub fn load_id_set(dir: &str) -> std::io::Result<HashSet<u64>> {
+    pub fn load_id_set(dir: &str) -> Result<HashSet<u64>> {
         let mut set = HashSet::new();
 
-        for entry in std::fs::read_dir(&dir)? {
+        for entry in std::fs::read_dir(dir)? {
             let entry = entry?;
             if entry.file_type()?.is_dir() {
                 continue;
             }
 
-            let id = fid(entry.file_name().to_str().unwrap());
-            if let Ok(id) = id {
+            if let Some(Ok(id)) = entry.file_name().to_str().map(fid) {
                 set.insert(id);
             }
         }
@@ -83,17 +76,11 @@ pub mod file_helper {
     }
 
     // use wal file name to get its fid
-    pub fn fid_wal(name: &str) -> Result<u64, String> {
-        if !name.ends_with(".wal") {
-            return Err("not a wal  file".to_string());
-        }
-
+    pub fn fid_wal(name: &str) -> Result<u64> {
         // remove the ".wal" suffix
-        let name = name.trim_end_matches(".wal");
-
-        match name.parse::<u64>() {
-            Ok(id) => Ok(id),
-            Err(e) => Err(e.to_string()),
-        }
+        let id = name
+            .strip_suffix(".wal")
+            .and_then(|id| id.parse::<u64>().ok());
+        id.ok_or_else(|| Error::InvalidArgument(format!("{} is not a wal file", name)))
     }
 }
@@ -1,5 +1,9 @@
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+// FILTER_VERSION is recorded in the index of a table with its bloom filter,
+// version 0 filters were hashed by DefaultHasher, which is not stable across
+// rust releases, so they are not used
+pub const FILTER_VERSION: u32 = 1;
+
+#[derive(Default)]
 pub struct Filter {
     filter: Vec<u8>,
 }
@@ -15,14 +19,31 @@ impl Filter {
         }
     }
 
-    pub fn hash(mut key: &[u8]) -> u32 {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        hasher.finish() as u32
+    // hash is the hash of leveldb with its bloom seed, filters on disk depend
+    // on it, so it must never change without a new FILTER_VERSION
+    pub fn hash(key: &[u8]) -> u32 {
+        const SEED: u32 = 0xbc9f1d34;
+        const M: u32 = 0xc6a4a793;
+        let mut h = SEED ^ (key.len() as u32).wrapping_mul(M);
+        let mut chunks = key.chunks_exact(4);
+        for chunk in &mut chunks {
+            let w = u32::from_le_bytes(chunk.try_into().unwrap());
+            h = h.wrapping_add(w).wrapping_mul(M);
+            h ^= h >> 16;
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            for (i, &b) in rest.iter().enumerate() {
+                h = h.wrapping_add((b as u32) << (8 * i));
+            }
+            h = h.wrapping_mul(M);
+            h ^= h >> 24;
+        }
+        h
     }
 
     pub fn bloom_bits_per_key(num_entries: i32, fp: f64) -> i32 {
-        let size = -1.0 * (num_entries as f64) * fp.ln() / f64::powf(0.69314718056, 2.0);
+        let size = -(num_entries as f64) * fp.ln() / std::f64::consts::LN_2.powi(2);
         let locs = size / num_entries as f64;
         locs.ceil() as i32
     }
@@ -48,7 +69,7 @@ impl Filter {
             return true;
         }
         let n_bits = 8 * (self.filter.len() - 1) as u32;
-        let delta = h >> 17 | h << 15;
+        let delta = h.rotate_left(15);
         for _ in 0..k {
             let bit_pos = h % n_bits;
             if self.filter[bit_pos as usize / 8] & (1 << (bit_pos % 8)) == 0 {
@@ -64,16 +85,9 @@ impl Filter {
             bits_per_key = 0;
         }
 
-        let mut k = (bits_per_key as f64 * 0.69) as u32;
+        let k = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
 
-        if k < 1 {
-            k = 1;
-        }
-        if k > 30 {
-            k = 30;
-        }
-
-        let mut nbits = keys.len() as i32 * bits_per_key as i32;
+        let mut nbits = keys.len() as i32 * bits_per_key;
 
         if nbits < 64 {
             nbits = 64;
@@ -83,7 +97,7 @@ impl Filter {
         let mut filter: Vec<u8> = vec![0; n_bytes as usize + 1];
         for &h in keys {
             let mut h = h;
-            let delta = h >> 17 | h << 15;
+            let delta = h.rotate_left(15);
             for _ in 0..k {
                 let bitpos = h % (n_bits as u32);
                 filter[(bitpos / 8) as usize] |= 1 << (bitpos % 8);
@@ -102,30 +116,12 @@ impl Filter {
 mod tests {
     use super::Filter;
 
-    impl Filter {
-        // Assuming the other methods like `hash`, `may_contain_key`, etc., are already implemented.
-
-        // Function to convert the filter to a string representation
-        pub fn to_string(&self) -> String {
-            let mut s = String::new();
-            for &byte in &self.filter {
-                for i in 0..8 {
-                    if byte & (1 << (7 - i)) != 0 {
-                        s.push('1');
-                    } else {
-                        s.push('.');
-                    }
-                }
-            }
-            s
-        }
-    }
     // Test for the `may_contain_key` method
     #[test]
     fn test_bloom_filter_may_contain_key() {
         let keys = vec![
-        Filter::hash(&"g".as_bytes().to_vec()),
-        Filter::hash(&"go".as_bytes().to_vec())
+        Filter::hash("g".as_bytes()),
+        Filter::hash("go".as_bytes())
         ];
 
         // Create the Bloom filter with keys and bits per key
@@ -167,6 +163,36 @@ mod tests {
         );
     }
 
+    // the hash is the hash of leveldb, the vectors are from its tests
+    #[test]
+    fn test_hash_golden() {
+        assert_eq!(Filter::hash(b""), 0xbc9f1d34);
+        assert_eq!(Filter::hash(&[0x62]), 0xef1345c4);
+        assert_eq!(Filter::hash(&[0xc3, 0x97]), 0x5b663814);
+        assert_eq!(Filter::hash(&[0xe2, 0x99, 0xa5]), 0x323c078f);
+        assert_eq!(Filter::hash(&[0xe1, 0x80, 0xb9, 0x32]), 0xed21633a);
+    }
+
+    // the filter of "key0".."key99" with 10 bits per key, as it is on disk in
+    // FILTER_VERSION tables, a change of it must come with a new version
+    #[test]
+    fn test_filter_golden() {
+        let golden = include_bytes!("testdata/filter_v1.golden");
+        let keys: Vec<u32> = (0..100)
+            .map(|i| Filter::hash(format!("key{}", i).as_bytes()))
+            .collect();
+        assert_eq!(Filter::with_keys(&keys, 10).get(), golden.to_vec());
+
+        let filter = Filter::with_filter(golden);
+        for i in 0..100 {
+            assert!(filter.may_contain_key(format!("key{}", i).as_bytes()));
+        }
+        let false_positives = (100..1100)
+            .filter(|i| filter.may_contain_key(format!("key{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 30);
+    }
+
     // Test for Bloom filter's bits per key calculation
     #[test]
     fn test_bloom_bits_per_key() {
@@ -1,6 +1,8 @@
 pub mod encodings;
 pub mod filter;
 //mod filter_outer;
+pub mod cache;
+pub mod compress;
 pub mod slice;
 pub mod file;
 pub mod test_helper;
\ No newline at end of file
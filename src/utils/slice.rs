@@ -1,6 +1,3 @@
-use crate::utils::encodings::*;
-use std::convert::From;
-use std::ops::Index;
 pub type Slice = Vec<u8>;
 
 
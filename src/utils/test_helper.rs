@@ -1,10 +1,7 @@
-use rand::{seq::IteratorRandom, Rng};
-use std::fmt;
+use crate::db::options::Options;
+use rand::seq::IteratorRandom;
 
 pub fn generate_incredible_strings(num: usize) -> Vec<String> {
-    // Define the alphabet
-    let alphabet = "abcdefghijklmnopqrstuvwxyz";
-
     // Starting string is "abc"
     let mut current_chars = vec!['a', 'b', 'c'];
 
@@ -35,18 +32,31 @@ pub fn generate_incredible_strings(num: usize) -> Vec<String> {
     result_vec // Return the vector of generated strings
 }
 
-pub fn display(s: &Vec<u8>) -> Result<String, std::string::FromUtf8Error> {
-    String::from_utf8(s.clone())
+pub fn display(s: &[u8]) -> Result<String, std::string::FromUtf8Error> {
+    String::from_utf8(s.to_vec())
 }
 
 pub fn work_dir_clear(dir: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
     for entry in std::fs::read_dir(dir)? {
         let entry = entry?;
         let path = entry.path();
-        std::fs::remove_file(path)?
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(path)?
+        } else {
+            std::fs::remove_file(path)?
+        }
     }
     Ok(())
 }
+
+// test options with a cleared work dir of its own, so tests can run in parallel
+pub fn test_options(name: &str) -> Options {
+    let mut opt = Options::test_new();
+    opt.work_dir = format!("{}/{}", opt.work_dir, name);
+    work_dir_clear(&opt.work_dir).unwrap();
+    opt
+}
 pub fn rand_str(length: usize) -> String {
     // Define the characters to choose from (including special characters and emojis)
     let chars = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ~=+%^*/()[]{}/!@#$?|©®😁😭🉑️🐂㎡我爱吃鱼";